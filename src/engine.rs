@@ -4,15 +4,87 @@
 // The engine will have a MEMOdb instance to store the data
 
 use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use serde_json::{json, Map, Value};
 use uuid::{uuid, Uuid};
 
+use crate::memodb::aggregate::Pipeline;
+use crate::memodb::bulk::{Bulk, Failure, Outcome};
+use crate::memodb::cursor::Cursor;
 use crate::memodb::data_type::DataType;
-use crate::{doc, memodb::MEMOdb};
-use crate::memodb::collection::{self, Document, DocumentJson};
+use crate::memodb::MEMOdb;
+use crate::memodb::error::DbError;
+use crate::memodb::index::IndexSpec;
+use crate::memodb::path::Projection;
+use crate::memodb::sort::Sort;
+use crate::memodb::query::Filter;
+use crate::memodb::transaction::Step;
+use crate::memodb::update::Update;
+use crate::memodb::wal::SyncPolicy;
+use crate::memodb::collection::{self, Collection, Document, DocumentJson};
 use crate::hteapot::{HteaPot, HttpMethod, HttpRequest};
 use crate::hteapot::HttpStatus;
 
 
+// How the documents of a response are returned:
+// ?fields= projection, ?sort= order, and ?limit= documents after the first ?offset= ones
+// or after the ?cursor= returned with the previous page
+struct Listing {
+  projection: Option<Projection>,
+  sort: Option<Sort>,
+  cursor: Option<Cursor>,
+  limit: usize,
+  offset: usize,
+  // the request, to link the next page
+  path: String,
+  args: Vec<(String, String)>,
+}
+
+impl Listing {
+  // the arguments that are not criteria of find
+  const ARGS: [&'static str; 5] = ["fields", "sort", "limit", "offset", "cursor"];
+
+  fn parse(request: &HttpRequest) -> Result<Listing, String> {
+    let args = &request.args;
+    let number = |name: &str| match args.get(name) {
+      Some(value) => value.parse::<usize>().map_err(|_| format!("{} must be a positive number", name)),
+      None => Ok(0)
+    };
+    let sort = args.get("sort").map(|sort| Sort::parse(sort)).transpose()?;
+    let mut link_args: Vec<(String, String)> = args.iter()
+      .filter(|(key, _)| *key != "cursor" && *key != "offset")
+      .map(|(key, value)| (key.clone(), value.clone()))
+      .collect();
+    link_args.sort();
+    Ok(Listing {
+      projection: args.get("fields").map(|fields| Projection::parse(fields)).transpose()?,
+      cursor: args.get("cursor").map(|cursor| Cursor::decode(cursor, sort.as_ref())).transpose()?,
+      sort,
+      limit: number("limit")?,
+      offset: number("offset")?,
+      path: request.path.clone(),
+      args: link_args,
+    })
+  }
+
+  // The page of the documents with the X-Total-Count header,
+  // and a Link header to the next page when there is one
+  fn respond(&self, collection: &Collection, documents: Vec<&Document>) -> String {
+    let page = collection.page(documents, self.sort.as_ref(), self.cursor.as_ref(), self.offset, self.limit);
+    let body = Engine::json_array(page.documents.into_iter(), self.projection.as_ref());
+    let mut headers = vec![("X-Total-Count", page.total.to_string())];
+    if let Some(next) = page.next {
+      let mut query: Vec<String> = self.args.iter()
+        .map(|(key, value)| format!("{}={}", HteaPot::percent_encode(key), HteaPot::percent_encode(value)))
+        .collect();
+      query.push(format!("cursor={}", next.encode()));
+      headers.push(("Link", format!("<{}?{}>; rel=\"next\"", self.path, query.join("&"))));
+    }
+    HteaPot::response_maker_with_headers(HttpStatus::OK, &body, &headers)
+  }
+}
+
 pub struct Engine {
   db: MEMOdb
 
@@ -25,13 +97,15 @@ impl Engine {
     }
   }
 
-  //TODO: remove this function, test only
-  pub fn init_mock_data(&mut self) {
-    let _ = self.db.create_collection("users".to_string());
-    let collection = self.db.get_collection("users".to_string()).unwrap();
-    collection.add(doc!{"name" => "John", "age" => 30});
-    collection.add(doc!{"name" => "Jane", "age" => 25});
-    collection.add(doc!{"name" => "Doe", "age" => 40});
+  // Load the database from the snapshot and replay the write-ahead log on top of it
+  pub fn open(snapshot_path: &Path, wal_path: &Path, sync: SyncPolicy) -> io::Result<Engine> {
+    Ok(Engine {
+      db: MEMOdb::open(snapshot_path, wal_path, sync)?
+    })
+  }
+
+  pub fn save(&self) -> io::Result<()> {
+    self.db.save()
   }
 
   //wrapper for MEMOdb functions
@@ -42,17 +116,24 @@ impl Engine {
     HteaPot::response_maker(HttpStatus::OK, &list)
   }
 
-  fn get_document_by_id(&mut self, collection_name: String, id: Uuid) -> String {
+  // The ETag header tells the version of the document,
+  // with an If-None-Match of that version the document is not sent again
+  fn get_document_by_id(&self, collection_name: String, id: Uuid, listing: &Listing, if_none_match: Option<&str>) -> String {
     let collection = self.db.get_collection(collection_name);
     match collection {
         Some(collection) => {
+            let collection = collection.snapshot();
+            let etag = collection.etag(id);
             let document = collection.get(id);
-            match document {
-                Some(document) => {
-                    let result = document.to_json();
-                    HteaPot::response_maker(HttpStatus::OK, &result)
+            match (document, etag) {
+                (Some(document), Some(etag)) => {
+                    if if_none_match.is_some_and(|header| Engine::etag_matches(Some(&etag), header, true)) {
+                        return HteaPot::response_maker_with_headers(HttpStatus::NotModified, "", &[("ETag", etag)]);
+                    }
+                    let result = Engine::document_json(document, listing.projection.as_ref());
+                    HteaPot::response_maker_with_headers(HttpStatus::OK, &result, &[("ETag", etag)])
                 }
-                None => {
+                _ => {
                     HteaPot::response_maker(HttpStatus::NotFound, "Not Found")
                 }
             }
@@ -63,20 +144,66 @@ impl Engine {
     }
   }
 
-  fn get_all_documents(&mut self, collection_name: String,limit: usize, offset: usize) -> String {
+  // Whether the ETag of a document is one of the list of an If-Match or If-None-Match header,
+  // `*` matches any document that exists
+  // If-None-Match compares weak tags (W/"...") as the same version, If-Match never matches them
+  fn etag_matches(etag: Option<&str>, header: &str, weak: bool) -> bool {
+    let etag = match etag {
+        Some(etag) => etag,
+        None => return false
+    };
+    header.split(',').map(str::trim).any(|tag| {
+        if tag == "*" {
+            return true;
+        }
+        match tag.strip_prefix("W/") {
+            Some(tag) => weak && tag == etag,
+            None => tag == etag
+        }
+    })
+  }
+
+  // The 412 response when the If-Match header doesn't match the version of the document `id`
+  fn precondition(collection: &Collection, id: Uuid, if_match: Option<&str>) -> Option<String> {
+    match if_match {
+        Some(header) if !Engine::etag_matches(collection.etag(id).as_deref(), header, false) => {
+            Some(HteaPot::response_maker(HttpStatus::PreconditionFailed, "Precondition Failed"))
+        }
+        _ => None
+    }
+  }
+
+  // only the fields of the projection, when there is one
+  fn document_json(document: &Document, projection: Option<&Projection>) -> String {
+    match projection {
+        Some(projection) => projection.apply(document).to_json(),
+        None => document.to_json()
+    }
+  }
+
+  fn json_array<'a>(documents: impl Iterator<Item = &'a Document>, projection: Option<&Projection>) -> String {
+    let mut body = String::from("[");
+    for document in documents {
+        let result = Engine::document_json(document, projection);
+        body.push_str(&result);
+        body.push(',');
+    }
+    if body.ends_with(',') { body.pop(); }
+    body.push(']');
+    body
+  }
+
+  fn get_all_documents(&self, collection_name: String, listing: &Listing) -> String {
     let collection = self.db.get_collection(collection_name);
     match collection {
         Some(collection) => {
-            let mut body = String::from("[");
-            let documents = collection.get_all(limit, offset);
-            for document in documents {
-                let result = document.to_json();
-                body.push_str(&result);
-                body.push_str(",");
-            }
-            if body.ends_with(',') { body.pop(); }
-            body.push_str("]");
-            HteaPot::response_maker(HttpStatus::OK, &body)
+            let collection = collection.snapshot();
+            let filter = Filter::And(Vec::new());
+            let documents = match &listing.sort {
+                Some(sort) => collection.query_sorted(&filter, sort),
+                None => collection.query(&filter)
+            };
+            listing.respond(&collection, documents)
         }
         None => {
             HteaPot::response_maker(HttpStatus::NotFound, "Not Found")
@@ -84,45 +211,439 @@ impl Engine {
     }
   }
 
-  fn find(&mut self, collection_name: String, args: HashMap<String,String>) -> String {
+  // The criteria of find in the query string, without the arguments of the listing,
+  // and whether any of them matches instead of all
+  fn find_criteria(args: HashMap<String,String>) -> Result<(HashMap<String, DataType>, bool), String> {
+    let mut args = args;
+    args.retain(|key, _| !Listing::ARGS.contains(&key.as_str()));
+    let match_any = match args.remove("match").as_deref() {
+        None | Some("all") => false,
+        Some("any") => true,
+        Some(_) => return Err("match must be all or any".to_string())
+    };
+    let args = args.iter().map(|(k, v)| (k.to_string(), DataType::from_json(v))).collect();
+    Ok((args, match_any))
+  }
+
+  fn find_filter(args: HashMap<String, DataType>, match_any: bool) -> Filter {
+    let criteria = Collection::criteria(args);
+    if match_any { Filter::Or(criteria) } else { Filter::And(criteria) }
+  }
+
+  // GET /{collection_name}/find?key=value, documents matching all the criteria
+  // or any of them with match=any, the keys can be dot paths like address.city
+  fn find(&self, collection_name: String, args: HashMap<String,String>, listing: &Listing) -> String {
+    let (args, match_any) = match Engine::find_criteria(args) {
+        Ok(criteria) => criteria,
+        Err(e) => return HteaPot::response_maker(HttpStatus::BadRequest, &e)
+    };
     let collection = self.db.get_collection(collection_name);
     match collection {
         Some(collection) => {
-        let mut body = String::from("[");
-        let args: HashMap<String, DataType> = args.iter().map(|(k, v)| (k.to_string(), DataType::from_json(v))).collect();
-        let documents: Vec<&Document> = collection.find(args);
-            for document in documents {
-                let result = document.to_json();
-                body.push_str(&result);
-                body.push_str(",");
+        let collection = collection.snapshot();
+        let documents: Vec<&Document> = match &listing.sort {
+            Some(sort) => collection.query_sorted(&Engine::find_filter(args, match_any), sort),
+            None if match_any => collection.find_any(args),
+            None => collection.find(args)
+        };
+            listing.respond(&collection, documents)
+        }
+        None => {
+            HteaPot::response_maker(HttpStatus::NotFound, "Not Found")
+        }
+    }
+  }
+
+  // GET /{collection_name}/_count?key=value, number of documents matching the criteria of find
+  fn count(&self, collection_name: String, args: HashMap<String,String>) -> String {
+    let (args, match_any) = match Engine::find_criteria(args) {
+        Ok(criteria) => criteria,
+        Err(e) => return HteaPot::response_maker(HttpStatus::BadRequest, &e)
+    };
+    match self.db.get_collection(collection_name) {
+        Some(collection) => {
+            let collection = collection.snapshot();
+            let count = if args.is_empty() {
+                collection.count()
+            } else if match_any {
+                collection.find_any(args).len()
+            } else {
+                collection.find(args).len()
+            };
+            HteaPot::response_maker(HttpStatus::OK, &json!({"count": count}).to_string())
+        }
+        None => {
+            HteaPot::response_maker(HttpStatus::NotFound, "Not Found")
+        }
+    }
+  }
+
+  // GET /{collection_name}/_distinct/{field}?key=value, values of the field in the documents
+  // matching the criteria of find
+  fn distinct(&self, collection_name: String, field: &str, args: HashMap<String,String>) -> String {
+    let (args, match_any) = match Engine::find_criteria(args) {
+        Ok(criteria) => criteria,
+        Err(e) => return HteaPot::response_maker(HttpStatus::BadRequest, &e)
+    };
+    match self.db.get_collection(collection_name) {
+        Some(collection) => {
+            let collection = collection.snapshot();
+            let values = collection.distinct(field, &Engine::find_filter(args, match_any));
+            let body = Value::Array(values.iter().map(DataType::to_value).collect());
+            HteaPot::response_maker(HttpStatus::OK, &body.to_string())
+        }
+        None => {
+            HteaPot::response_maker(HttpStatus::NotFound, "Not Found")
+        }
+    }
+  }
+
+  // find with a JSON filter supporting the query operators
+  fn query(&self, collection_name: String, body: &str, listing: &Listing) -> String {
+    let filter = match Filter::from_json(body) {
+        Ok(filter) => filter,
+        Err(e) => return HteaPot::response_maker(HttpStatus::BadRequest, &e)
+    };
+    match self.db.get_collection(collection_name) {
+        Some(collection) => {
+            let collection = collection.snapshot();
+            let documents = match &listing.sort {
+                Some(sort) => collection.query_sorted(&filter, sort),
+                None => collection.query(&filter)
+            };
+            listing.respond(&collection, documents)
+        }
+        None => {
+            HteaPot::response_maker(HttpStatus::NotFound, "Not Found")
+        }
+    }
+  }
+
+  // POST /{collection_name}/_aggregate with a pipeline of stages as body
+  fn aggregate(&self, collection_name: String, body: &str) -> String {
+    let pipeline = match Pipeline::from_json(body) {
+        Ok(pipeline) => pipeline,
+        Err(e) => return HteaPot::response_maker(HttpStatus::BadRequest, &e)
+    };
+    match self.db.get_collection(collection_name) {
+        Some(collection) => {
+            let collection = collection.snapshot();
+            let documents = pipeline.run(&collection);
+            HteaPot::response_maker(HttpStatus::OK, &Engine::json_array(documents.iter(), None))
+        }
+        None => {
+            HteaPot::response_maker(HttpStatus::NotFound, "Not Found")
+        }
+    }
+  }
+
+  // POST /{collection_name}/_update with {"filter": {...}, "update": {...}} as body
+  // POST /{collection_name}/_delete with {"filter": {...}} as body
+  // Every matching document is written or none, "dry_run": true returns the IDs without writing
+  fn write_many(&self, collection_name: String, body: &str, delete: bool) -> String {
+    let object = match Engine::json_object(body) {
+        Ok(object) => object,
+        Err(e) => return HteaPot::response_maker(HttpStatus::BadRequest, &e)
+    };
+    let filter = match object.get("filter").map(Filter::from_value) {
+        Some(Ok(filter)) => filter,
+        Some(Err(e)) => return HteaPot::response_maker(HttpStatus::BadRequest, &e),
+        None => return HteaPot::response_maker(HttpStatus::BadRequest, "The filter is missing, {} matches every document")
+    };
+    let update = match (delete, object.get("update").map(Update::from_value)) {
+        (true, _) => None,
+        (false, Some(Ok(update))) => Some(update),
+        (false, Some(Err(e))) => return HteaPot::response_maker(HttpStatus::BadRequest, &e),
+        (false, None) => return HteaPot::response_maker(HttpStatus::BadRequest, "The update is missing")
+    };
+    let dry_run = match object.get("dry_run") {
+        None => false,
+        Some(Value::Bool(dry_run)) => *dry_run,
+        Some(_) => return HteaPot::response_maker(HttpStatus::BadRequest, "dry_run must be true or false")
+    };
+    let collection = match self.db.get_collection(collection_name) {
+        Some(collection) => collection,
+        None => return HteaPot::response_maker(HttpStatus::NotFound, "Not Found")
+    };
+    let mut collection = collection.write();
+    let result = match &update {
+        Some(update) => collection.update_many(&filter, update, dry_run),
+        None => collection.delete_many(&filter, dry_run)
+    };
+    match result {
+        Ok(result) => {
+            let ids: Vec<String> = result.ids.iter().map(|id| id.to_string()).collect();
+            let changed = if delete { "deleted" } else { "modified" };
+            let body = json!({"matched": result.matched, changed: ids.len(), "ids": ids, "dry_run": dry_run});
+            HteaPot::response_maker(HttpStatus::OK, &body.to_string())
+        }
+        Err(e) => Engine::error_response(e)
+    }
+  }
+
+  // POST /{collection_name}/_bulk with a JSON array or NDJSON of operations as body
+  // ordered=false runs every operation even after a failure
+  fn bulk(&self, collection_name: String, body: &str, ordered: bool) -> String {
+    let bulk = match Bulk::parse(body) {
+        Ok(bulk) => bulk,
+        Err(e) => return HteaPot::response_maker(HttpStatus::BadRequest, &e)
+    };
+    let collection = match self.db.get_collection(collection_name) {
+        Some(collection) => collection,
+        None => return HteaPot::response_maker(HttpStatus::NotFound, "Not Found")
+    };
+    let mut collection = collection.write();
+    let mut counts: HashMap<&str, usize> = HashMap::from([("inserted", 0), ("updated", 0), ("replaced", 0), ("upserted", 0), ("deleted", 0), ("errors", 0)]);
+    let mut results = Vec::with_capacity(bulk.len());
+    for (index, result) in bulk.run(&mut collection, ordered).into_iter().enumerate() {
+        let (count, result) = match result {
+            Ok(Outcome::Inserted(id)) => ("inserted", json!({"index": index, "status": HttpStatus::Created as u16, "id": id.to_string()})),
+            Ok(Outcome::Updated(id)) => ("updated", json!({"index": index, "status": HttpStatus::OK as u16, "id": id.to_string()})),
+            Ok(Outcome::Replaced { id, added: true }) => ("upserted", json!({"index": index, "status": HttpStatus::Created as u16, "id": id.to_string()})),
+            Ok(Outcome::Replaced { id, added: false }) => ("replaced", json!({"index": index, "status": HttpStatus::OK as u16, "id": id.to_string()})),
+            Ok(Outcome::Deleted(id) | Outcome::Moved(id)) => ("deleted", json!({"index": index, "status": HttpStatus::OK as u16, "id": id.to_string()})),
+            Err(Failure::Invalid(e)) => ("errors", json!({"index": index, "status": HttpStatus::BadRequest as u16, "error": e})),
+            Err(Failure::Db(e)) => ("errors", json!({"index": index, "status": Engine::error_status(&e) as u16, "error": e.to_string()})),
+            Err(Failure::Skipped) => {
+                results.push(json!({"index": index, "skipped": true}));
+                continue;
+            }
+        };
+        *counts.get_mut(count).unwrap() += 1;
+        results.push(result);
+    }
+    let mut body = json!(counts);
+    body["ordered"] = json!(ordered);
+    body["results"] = Value::Array(results);
+    HteaPot::response_maker(HttpStatus::OK, &body.to_string())
+  }
+
+  // POST /_transaction with a list of operations as body, all of them are written or none
+  fn transaction(&self, body: &str) -> String {
+    let steps = match Step::parse_list(body) {
+        Ok(steps) => steps,
+        Err(e) => return HteaPot::response_maker(HttpStatus::BadRequest, &e)
+    };
+    let mut failed = 0;
+    let result = self.db.transaction(|tx| {
+        let mut results = Vec::new();
+        for (index, step) in steps.into_iter().enumerate() {
+            failed = index;
+            let (status, id) = match tx.run(step)? {
+                Outcome::Inserted(id) | Outcome::Replaced { id, added: true } => (HttpStatus::Created, id),
+                Outcome::Updated(id) | Outcome::Replaced { id, .. } | Outcome::Deleted(id) | Outcome::Moved(id) => (HttpStatus::OK, id),
+            };
+            results.push(json!({"index": index, "status": status as u16, "id": id.to_string()}));
+        }
+        Ok(results)
+    });
+    match result {
+        Ok(results) => {
+            let body = json!({"committed": true, "results": results});
+            HteaPot::response_maker(HttpStatus::OK, &body.to_string())
+        }
+        Err(DbError::Io(e)) => Engine::error_response(DbError::Io(e)),
+        Err(e) => {
+            let body = json!({"committed": false, "index": failed, "error": e.to_string()});
+            HteaPot::response_maker(Engine::error_status(&e), &body.to_string())
+        }
+    }
+  }
+
+  // The body of a request as a JSON object
+  fn json_object(body: &str) -> Result<Map<String, Value>, String> {
+    match serde_json::from_str(body) {
+        Ok(Value::Object(object)) => Ok(object),
+        Ok(_) => Err("The body must be a JSON object".to_string()),
+        Err(e) => Err(e.to_string())
+    }
+  }
+
+  // PUT /{collection_name}/{id} with the new document as body, the document is added
+  // with that ID when it doesn't exist, unless upsert=false is given
+  fn replace_document(&self, collection_name: String, id: &str, body: &str, upsert: bool, if_match: Option<&str>) -> String {
+    let id = match id.parse::<Uuid>() {
+        Ok(id) => id,
+        Err(_) => return HteaPot::response_maker(HttpStatus::BadRequest, "Bad Request")
+    };
+    if let Err(e) = Engine::json_object(body) {
+        return HteaPot::response_maker(HttpStatus::BadRequest, &e);
+    }
+    let document: Document = DocumentJson::from_json(body);
+    match self.db.get_collection(collection_name) {
+        Some(collection) => {
+            let mut collection = collection.write();
+            if let Some(response) = Engine::precondition(&collection, id, if_match) {
+                return response;
             }
-            if body.ends_with(',') {body.pop();}
-            body.push_str("]");
-            HteaPot::response_maker(HttpStatus::OK, &body)
+            let status = match collection.replace_document(id, document, upsert) {
+                Ok((_, true)) => HttpStatus::Created,
+                Ok((_, false)) => HttpStatus::OK,
+                Err(e) => return Engine::error_response(e)
+            };
+            let etag = collection.etag(id).unwrap();
+            HteaPot::response_maker_with_headers(status, &collection.get(id).unwrap().to_json(), &[("ETag", etag)])
+        },
+        None => {
+            HteaPot::response_maker(HttpStatus::NotFound, "Not Found")
         }
+    }
+  }
+
+  // PATCH /{collection_name}/{id}, returns the updated document
+  // A body with update operators is applied as an update, any other body is merged into the document
+  fn patch_document(&self, collection_name: String, id: &str, body: &str, if_match: Option<&str>) -> String {
+    let id = match id.parse::<Uuid>() {
+        Ok(id) => id,
+        Err(_) => return HteaPot::response_maker(HttpStatus::BadRequest, "Bad Request")
+    };
+    let object = match Engine::json_object(body) {
+        Ok(object) => object,
+        Err(e) => return HteaPot::response_maker(HttpStatus::BadRequest, &e)
+    };
+    let update = if object.keys().any(|key| key.starts_with('$')) {
+        match Update::from_value(&Value::Object(object)) {
+            Ok(update) => Some(update),
+            Err(e) => return HteaPot::response_maker(HttpStatus::BadRequest, &e)
+        }
+    } else {
+        None
+    };
+    match self.db.get_collection(collection_name) {
+        Some(collection) => {
+            let mut collection = collection.write();
+            if let Some(response) = Engine::precondition(&collection, id, if_match) {
+                return response;
+            }
+            let result = match &update {
+                Some(update) => collection.patch_document(id, update),
+                None => collection.update_document(id, DocumentJson::from_json(body))
+            };
+            let document = match result {
+                Ok(document) => document.to_json(),
+                Err(e) => return Engine::error_response(e)
+            };
+            HteaPot::response_maker_with_headers(HttpStatus::OK, &document, &[("ETag", collection.etag(id).unwrap())])
+        },
         None => {
             HteaPot::response_maker(HttpStatus::NotFound, "Not Found")
         }
     }
   }
 
-  fn delete_collection(&mut self, collection_name: String) -> String {
-    let collection = self.db.remove_collection(collection_name);
-    let result = format!("{{\"collection\": \"{}\"}}", collection.name);
-    HteaPot::response_maker(HttpStatus::OK, &result)
+  fn error_status(error: &DbError) -> HttpStatus {
+    match error {
+      DbError::CollectionNotFound | DbError::DocumentNotFound | DbError::IndexNotFound => HttpStatus::NotFound,
+      DbError::CollectionExists => HttpStatus::NotModified,
+      DbError::UniqueViolation { .. } | DbError::DocumentExists | DbError::IndexExists => HttpStatus::Conflict,
+      DbError::InvalidUpdate(_) => HttpStatus::BadRequest,
+      DbError::Io(_) => HttpStatus::InternalServerError,
+    }
+  }
+
+  fn error_response(error: DbError) -> String {
+    let status = Engine::error_status(&error);
+    match error {
+      DbError::UniqueViolation { ref index, id } => {
+        let body = json!({"error": error.to_string(), "index": index, "id": id.to_string()});
+        HteaPot::response_maker(status, &body.to_string())
+      }
+      DbError::Io(_) => {
+        eprintln!("Error: {}", error);
+        HteaPot::response_maker(status, "Internal Server Error")
+      }
+      _ => HteaPot::response_maker(status, &error.to_string())
+    }
   }
 
-  fn delete_document(&mut self, collection_name: String, document: String) -> String {
+  fn get_indexes(&self, collection_name: String) -> String {
+    match self.db.get_collection(collection_name) {
+        Some(collection) => {
+            let collection = collection.snapshot();
+            let indexes: Vec<Value> = collection.get_indexes().iter().map(|spec| {
+                let fields: Vec<String> = spec.fields.iter().map(|field| field.to_string()).collect();
+                json!({"name": spec.name, "fields": fields, "unique": spec.unique})
+            }).collect();
+            HteaPot::response_maker(HttpStatus::OK, &Value::from(indexes).to_string())
+        }
+        None => HteaPot::response_maker(HttpStatus::NotFound, "Not Found")
+    }
+  }
+
+  // body: {"fields": ["tenant", "-created_at"], "unique": true, "name": "tenant_created_at"}
+  // a field starting with - is descending, a single field can be given as {"field": "age"},
+  // the name defaults to the fields joined by _
+  fn create_index(&self, collection_name: String, body: &str) -> String {
+    let body: Value = match serde_json::from_str(body) {
+        Ok(body) => body,
+        Err(e) => return HteaPot::response_maker(HttpStatus::BadRequest, &e.to_string())
+    };
+    let fields: Option<Vec<String>> = match (body.get("field"), body.get("fields")) {
+        (Some(Value::String(field)), None) => Some(vec![field.clone()]),
+        (None, Some(Value::Array(fields))) if !fields.is_empty() => {
+            fields.iter().map(|field| field.as_str().map(String::from)).collect()
+        }
+        _ => None
+    };
+    let fields = match fields {
+        Some(fields) => fields,
+        None => return HteaPot::response_maker(HttpStatus::BadRequest, "field or fields is required")
+    };
+    let name = match body.get("name").and_then(Value::as_str) {
+        Some(name) => name.to_string(),
+        None => fields.join("_")
+    };
+    let unique = body.get("unique").and_then(Value::as_bool).unwrap_or(false);
+    match self.db.get_collection(collection_name) {
+        Some(collection) => {
+          let mut collection = collection.write();
+          let fields: Vec<&str> = fields.iter().map(String::as_str).collect();
+          match collection.create_index(IndexSpec::new(&name, &fields, unique)) {
+            Ok(_) => HteaPot::response_maker(HttpStatus::Created, &json!({"name": name}).to_string()),
+            Err(e) => Engine::error_response(e)
+          }
+        }
+        None => HteaPot::response_maker(HttpStatus::NotFound, "Not Found")
+    }
+  }
+
+  fn drop_index(&self, collection_name: String, name: &str) -> String {
+    match self.db.get_collection(collection_name) {
+        Some(collection) => match collection.write().drop_index(name) {
+            Ok(_) => HteaPot::response_maker(HttpStatus::OK, "OK"),
+            Err(e) => Engine::error_response(e)
+        },
+        None => HteaPot::response_maker(HttpStatus::NotFound, "Not Found")
+    }
+  }
+
+  fn delete_collection(&self, collection_name: String) -> String {
+    match self.db.remove_collection(collection_name) {
+      Ok(collection) => {
+        let result = format!("{{\"collection\": \"{}\"}}", collection.snapshot().name);
+        HteaPot::response_maker(HttpStatus::OK, &result)
+      }
+      Err(e) => Engine::error_response(e)
+    }
+  }
+
+  fn delete_document(&self, collection_name: String, document: String, if_match: Option<&str>) -> String {
     let id = Uuid::parse_str(document.as_str());
     if id.is_err() {
-        HteaPot::response_maker(HttpStatus::BadRequest, "Invalid id");
+        return HteaPot::response_maker(HttpStatus::BadRequest, "Invalid id");
     }
     let id = id.unwrap();
     let collection = self.db.get_collection(collection_name);
     match collection {
         Some(collection) => {
-            collection.rm(id);
-            HteaPot::response_maker(HttpStatus::OK, "OK")
+            let mut collection = collection.write();
+            if let Some(response) = Engine::precondition(&collection, id, if_match) {
+                return response;
+            }
+            match collection.rm(id) {
+                Ok(_) => HteaPot::response_maker(HttpStatus::OK, "OK"),
+                Err(e) => Engine::error_response(e)
+            }
         }
         None => {
             HteaPot::response_maker(HttpStatus::NotFound, "Not Found")
@@ -131,14 +652,20 @@ impl Engine {
   }
 
   //process the request and return the response
-  pub fn process(&mut self, request: HttpRequest) -> String {
+  pub fn process(&self, request: HttpRequest) -> String {
     let mut path = request.path.split("/").collect::<Vec<&str>>();
     path.retain(|&x| x != "");
     let collection_name:Option<String> =  if path.len() >= 1 {Some(path[0].to_string())} else {None};
     let document_name: Option<String> =  if path.len() >= 2 {Some(path[1].to_string())} else {None};
+    // PATH /{collection_name}/_indexes/{index_name} or /{collection_name}/_distinct/{field}
+    let index_name: Option<String> = path.get(2).map(|x| x.to_string());
     println!("PATH {:?}",path);
     println!("col {:?}", collection_name);
     println!("doc {:?}", document_name);
+    let listing = match Listing::parse(&request) {
+        Ok(listing) => listing,
+        Err(e) => return HteaPot::response_maker(HttpStatus::BadRequest, &e)
+    };
 
 
     match request.method {
@@ -168,19 +695,23 @@ impl Engine {
             let d = document_name.as_str();
             match d {
                 "all" => {
-                    let limit = request.args.get("limit").unwrap_or(&"0".to_string()).parse::<usize>().unwrap();
-                    let offset = request.args.get("offset").unwrap_or(&"0".to_string()).parse::<usize>().unwrap();
-                    self.get_all_documents(collection_name,limit, offset)
+                    self.get_all_documents(collection_name, &listing)
                 }
                 "find" => {
                     println!("args: {:?}", request.args);
-                    self.find(collection_name, request.args)
+                    self.find(collection_name, request.args, &listing)
                 }
+                "_indexes" => self.get_indexes(collection_name),
+                "_count" => self.count(collection_name, request.args),
+                "_distinct" => match index_name {
+                    Some(field) => self.distinct(collection_name, &field, request.args),
+                    None => HteaPot::response_maker(HttpStatus::BadRequest, "The field is missing, /_distinct/{field}")
+                },
                 _ => {
                     let id = document_name.parse::<Uuid>();
                     match id {
                         Ok(id) => {
-                            self.get_document_by_id(collection_name, id)
+                            self.get_document_by_id(collection_name, id, &listing, request.header("If-None-Match"))
                         }
                         Err(_) => {
                             HteaPot::response_maker(HttpStatus::BadRequest, "Bad Request")
@@ -195,25 +726,48 @@ impl Engine {
                 return HteaPot::response_maker(HttpStatus::BadRequest, "Bad request");
             }
             let collection_name = collection_name.unwrap();
+            if collection_name == "_transaction" && document_name.is_none() {
+                return self.transaction(&request.body);
+            }
             if document_name.is_none() {
                 let result = self.db.create_collection(collection_name);
                 match result {
                     Ok(_) => {
                         HteaPot::response_maker(HttpStatus::Created, "Created")
                     }, 
-                    Err(_) => {
-                        HteaPot::response_maker(HttpStatus::NotModified, "Collection already exists")
-                    }
+                    Err(e) => Engine::error_response(e)
                 }
+            } else if document_name.as_deref() == Some("find") {
+                // POST /{collection_name}/find with a JSON filter as body
+                self.query(collection_name, &request.body, &listing)
+            } else if document_name.as_deref() == Some("_indexes") {
+                self.create_index(collection_name, &request.body)
+            } else if document_name.as_deref() == Some("_aggregate") {
+                self.aggregate(collection_name, &request.body)
+            } else if document_name.as_deref() == Some("_bulk") {
+                let ordered = request.args.get("ordered").map(|x| x.as_str()) != Some("false");
+                self.bulk(collection_name, &request.body, ordered)
+            } else if document_name.as_deref() == Some("_update") {
+                self.write_many(collection_name, &request.body, false)
+            } else if document_name.as_deref() == Some("_delete") {
+                self.write_many(collection_name, &request.body, true)
             } else {
+                println!("Request body: {}", request.body);
+                // the body is parsed before taking the writer of the collection
+                if let Err(e) = Engine::json_object(&request.body) {
+                    return HteaPot::response_maker(HttpStatus::BadRequest, &e);
+                }
+                let document: Document = DocumentJson::from_json(&request.body);
                 let collection = self.db.get_collection(collection_name);
                 match collection {
                     Some(collection) => {
-                        println!("Request body: {}", request.body);
-                        let document: Document = DocumentJson::from_json(&request.body);
-                        let id = collection.add(document);
-                        let result = format!("{{\"id\":{}}}", id);
-                        HteaPot::response_maker(HttpStatus::Created, &result)
+                        match collection.write().add(document) {
+                            Ok(id) => {
+                                let result = format!("{{\"id\":{}}}", id);
+                                HteaPot::response_maker(HttpStatus::Created, &result)
+                            }
+                            Err(e) => Engine::error_response(e)
+                        }
                     }
                     None => {
                         HteaPot::response_maker(HttpStatus::NotFound, "Not Found")
@@ -227,9 +781,15 @@ impl Engine {
                 return HteaPot::response_maker(HttpStatus::BadRequest, "Bad request");
             }
             let collection_name = collection_name.unwrap();
+            if document_name.as_deref() == Some("_indexes") {
+                return match index_name {
+                    Some(index_name) => self.drop_index(collection_name, &index_name),
+                    None => HteaPot::response_maker(HttpStatus::BadRequest, "Bad Request")
+                };
+            }
             if document_name.is_some() {
                 let document = document_name.unwrap();
-                return self.delete_document(collection_name, document)
+                return self.delete_document(collection_name, document, request.header("If-Match"))
             } else {
                 let confirmation = request.headers.get("amisure");
                 match confirmation {
@@ -247,21 +807,22 @@ impl Engine {
             }
         }
         HttpMethod::PUT => {
-            if collection_name.is_some() && document_name.is_some() {
-                let collection_name  = collection_name.unwrap();
-                let document_name = document_name.unwrap();
-                let new_document: Document = DocumentJson::from_json(&request.body);
-                let collection = self.db.get_collection(collection_name);
-                if collection.is_none() {return HteaPot::response_maker(HttpStatus::NotFound, "Collection not found"); }
-                let collection = collection.unwrap();
-                let id = Uuid::parse_str(document_name.as_str());
-                if id.is_err() {return HteaPot::response_maker(HttpStatus::BadRequest, "Bad request"); };
-                let id = id.unwrap();
-                collection.update_document(id, new_document);
-                return HteaPot::response_maker(HttpStatus::OK, "Updated");
-
-            } else {
-                return HteaPot::response_maker(HttpStatus::BadRequest, "Bad request");
+            // PATH /{collection_name}/{id}
+            match (collection_name, document_name) {
+                (Some(collection_name), Some(document_name)) => {
+                    let upsert = request.args.get("upsert").map(|x| x.as_str());
+                    self.replace_document(collection_name, &document_name, &request.body, upsert != Some("false"), request.header("If-Match"))
+                }
+                _ => HteaPot::response_maker(HttpStatus::BadRequest, "Bad request")
+            }
+        }
+        HttpMethod::PATCH => {
+            // PATH /{collection_name}/{id}
+            match (collection_name, document_name) {
+                (Some(collection_name), Some(document_name)) => {
+                    self.patch_document(collection_name, &document_name, &request.body, request.header("If-Match"))
+                }
+                _ => HteaPot::response_maker(HttpStatus::BadRequest, "Bad request")
             }
         }
         _ => {
@@ -5,10 +5,12 @@
 // Info: This will be turn into a library
 
 use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::Arc;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use rayon::ThreadPoolBuilder;
 
 #[derive(Debug)]
@@ -27,18 +29,18 @@ pub enum HttpMethod {
 
 
 impl HttpMethod {
-    fn from_str(method: &str) -> HttpMethod {
+    fn from_str(method: &str) -> Option<HttpMethod> {
         match method {
-            "GET" => HttpMethod::GET,
-            "POST" => HttpMethod::POST,
-            "PUT" => HttpMethod::PUT,
-            "DELETE" => HttpMethod::DELETE,
-            "PATCH" => HttpMethod::PATCH,
-            "HEAD" => HttpMethod::HEAD,
-            "OPTIONS" => HttpMethod::OPTIONS,
-            "TRACE" => HttpMethod::TRACE,
-            "CONNECT" => HttpMethod::CONNECT,
-            _ => panic!("Invalid HTTP method"),
+            "GET" => Some(HttpMethod::GET),
+            "POST" => Some(HttpMethod::POST),
+            "PUT" => Some(HttpMethod::PUT),
+            "DELETE" => Some(HttpMethod::DELETE),
+            "PATCH" => Some(HttpMethod::PATCH),
+            "HEAD" => Some(HttpMethod::HEAD),
+            "OPTIONS" => Some(HttpMethod::OPTIONS),
+            "TRACE" => Some(HttpMethod::TRACE),
+            "CONNECT" => Some(HttpMethod::CONNECT),
+            _ => None,
         }
     }
     fn to_str(&self) -> &str {
@@ -69,7 +71,11 @@ pub enum HttpStatus {
     Unauthorized = 401,
     Forbidden = 403,
     NotFound = 404,
+    Conflict = 409,
+    PreconditionFailed = 412,
+    PayloadTooLarge = 413,
     IAmATeapot = 418,
+    RequestHeaderFieldsTooLarge = 431,
     InternalServerError = 500,
     NotImplemented = 501,
     BadGateway = 502,
@@ -78,7 +84,7 @@ pub enum HttpStatus {
 
 
 impl HttpStatus {
-    fn to_string(&self) -> &str {
+    fn to_string(&self) -> &'static str {
         match self {
             HttpStatus::OK => "OK",
             HttpStatus::Created => "Created",
@@ -91,7 +97,11 @@ impl HttpStatus {
             HttpStatus::Unauthorized => "Unauthorized",
             HttpStatus::Forbidden => "Forbidden",
             HttpStatus::NotFound => "Not Found",
+            HttpStatus::Conflict => "Conflict",
+            HttpStatus::PreconditionFailed => "Precondition Failed",
+            HttpStatus::PayloadTooLarge => "Payload Too Large",
             HttpStatus::IAmATeapot => "I'm a teapot",
+            HttpStatus::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
             HttpStatus::InternalServerError => "Internal Server Error",
             HttpStatus::NotImplemented => "Not Implemented",
             HttpStatus::BadGateway => "Bad Gateway",
@@ -105,15 +115,138 @@ impl HttpStatus {
 pub struct HttpRequest {
     pub method: HttpMethod,
     pub path: String,
+    // HTTP/1.1, or HTTP/1.0 for the clients not telling it
+    pub version: String,
     pub args: HashMap<String, String>,
     pub headers: HashMap<String, String>,
     pub body: String,
 }
 
+impl HttpRequest {
+    // The value of a header, the names of the headers are not case sensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    // Whether the client wants the connection open after the response,
+    // the default of HTTP/1.1 unless it sends Connection: close, HTTP/1.0 only with Connection: keep-alive
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("Connection").unwrap_or("").to_ascii_lowercase();
+        let has = |option: &str| connection.split(',').any(|x| x.trim() == option);
+        if self.version == "HTTP/1.0" { has("keep-alive") } else { !has("close") }
+    }
+}
+
+
+// What to do with a new connection when every worker is busy and the queue is full
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overload {
+    // answer 503 Service Unavailable and close it
+    Reject,
+    // stop accepting until a worker is free, the new connections wait in the backlog of the OS
+    Wait,
+}
+
+impl Overload {
+    pub fn parse(value: &str) -> Option<Overload> {
+        match value {
+            "reject" => Some(Overload::Reject),
+            "wait" => Some(Overload::Wait),
+            _ => None,
+        }
+    }
+}
+
+// The workers that handle the connections
+#[derive(Clone, Copy, Debug)]
+pub struct PoolConfig {
+    pub workers: usize,
+    // connections accepted waiting for a worker
+    pub queue_depth: usize,
+    pub overload: Overload,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig { workers: 16, queue_depth: 128, overload: Overload::Reject }
+    }
+}
+
+// Connections accepted and not finished yet, running or waiting for a worker
+struct Pending {
+    count: Mutex<usize>,
+    freed: Condvar,
+    workers: usize,
+}
+
+// How long the rest of a request not read is read and dropped before closing its connection
+const LINGER_TIMEOUT: Duration = Duration::from_millis(200);
+
+// Bytes of the largest head accepted, the request line and the headers,
+// and of the largest line of a chunked body
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+// The stream of a connection read until a deadline, every read waits only for the time left,
+// so a client sending a byte now and then can't keep a worker longer than that
+struct Timed {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Timed {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buffer)
+    }
+}
+
+// Why a request could not be read
+enum ReadError {
+    Io(io::Error),
+    // answered with this status and the connection closed
+    Status(HttpStatus, &'static str),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(value: io::Error) -> Self {
+        ReadError::Io(value)
+    }
+}
+
+// A place taken in the pool by a connection, given back when it is dropped,
+// even if the handler panics
+struct Slot(Arc<Pending>);
+
+impl Slot {
+    // Whether some connection is waiting for a worker,
+    // a connection kept open would make it wait until the idle timeout
+    fn crowded(&self) -> bool {
+        *self.0.count.lock().unwrap() > self.0.workers
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        *self.0.count.lock().unwrap() -= 1;
+        self.0.freed.notify_one();
+    }
+}
 
 pub struct HteaPot {
     port: u16,
     address: String,
+    pool: PoolConfig,
+    // a connection with no request for this long is closed, zero closes every connection after its response
+    idle_timeout: Duration,
+    // a request not read whole this long after its first byte, or after the connection is
+    // accepted for the first one, is dropped with its connection
+    request_timeout: Duration,
+    // bytes of the largest body accepted, a larger one is answered with 413 Payload Too Large
+    max_body_size: usize,
     // this will store a map from path to their actions
     // path_table: HashMap<HttpMethod, HashMap<String, HashMap<HttpMethod, fn(HttpRequest) -> String>>>,
 }
@@ -125,10 +258,38 @@ impl HteaPot {
         HteaPot {
             port: port,
             address: address.to_string(),
+            pool: PoolConfig::default(),
+            idle_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            max_body_size: 16 * 1024 * 1024,
             // path_table: HashMap::new(),
         }
     }
 
+    // Set the workers handling the connections
+    pub fn with_pool(mut self, pool: PoolConfig) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    // Set how long a connection is kept open waiting for its next request
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    // Set how long a client has to send a whole request
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    // Set the size in bytes of the largest body accepted
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
     // Start the server
     pub fn listen(&self, action: impl Fn(HttpRequest) -> String + Send + Sync + 'static ){
         let addr = format!("{}:{}", self.address, self.port);
@@ -140,17 +301,52 @@ impl HteaPot {
                 return;
             }
         };
+        self.serve(listener, action);
+    }
+
+    // Handle the connections of `listener` with the workers of the pool
+    fn serve(&self, listener: TcpListener, action: impl Fn(HttpRequest) -> String + Send + Sync + 'static) {
+        let workers = ThreadPoolBuilder::new()
+            .num_threads(self.pool.workers)
+            .thread_name(|index| format!("hteapot-{}", index))
+            // a request that makes the handler panic only loses its connection
+            .panic_handler(|_| eprintln!("Error: the request handler panicked"))
+            .build();
+        let workers = match workers {
+            Ok(workers) => workers,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return;
+            }
+        };
+        let limit = self.pool.workers + self.pool.queue_depth;
+        let pending = Arc::new(Pending { count: Mutex::new(0), freed: Condvar::new(), workers: self.pool.workers });
+        let (idle_timeout, request_timeout, max_body_size) = (self.idle_timeout, self.request_timeout, self.max_body_size);
         let action_clone = Arc::new(action);
+        // the rejected connections linger in their own thread, never in the accepting one
+        let (rejected, lingering) = mpsc::channel();
+        thread::spawn(move || HteaPot::linger(lingering));
         for stream in listener.incoming() {
             match stream {
                  Ok(stream) => {
+                    let mut count = pending.count.lock().unwrap();
+                    if *count >= limit && self.pool.overload == Overload::Reject {
+                        drop(count);
+                        HteaPot::reject(stream, &rejected);
+                        continue;
+                    }
+                    while *count >= limit {
+                        count = pending.freed.wait(count).unwrap();
+                    }
+                    *count += 1;
+                    drop(count);
+                    let slot = Slot(pending.clone());
                     let action_clone = action_clone.clone();
-                    thread::spawn(move || {
-                                HteaPot::handle_client(stream, |req| {
-                                    action_clone(req)
-                                });
+                    workers.spawn(move || {
+                        HteaPot::handle_client(stream, idle_timeout, request_timeout, max_body_size, &slot, |req| {
+                            action_clone(req)
+                        });
                     });
-   
                 }
                 Err(e) => {
                     println!("Error: {}", e);
@@ -159,38 +355,146 @@ impl HteaPot {
         }
     }
 
+    // Answer a connection the pool has no place for
+    // This runs in the accepting thread, so it never waits for the client, the response fits
+    // in the empty send buffer and the rest of the request is dropped by the linger thread
+    fn reject(stream: TcpStream, rejected: &Sender<TcpStream>) {
+        let response = HteaPot::with_connection(HteaPot::response_maker(HttpStatus::ServiceUnavailable, "Service Unavailable"), false);
+        if stream.set_nonblocking(true).is_err() || (&stream).write_all(response.as_bytes()).is_err() {
+            return;
+        }
+        let _ = stream.shutdown(Shutdown::Write);
+        let _ = rejected.send(stream);
+    }
+
+    // Read and drop what the rejected connections still send until they close or for
+    // LINGER_TIMEOUT, all of them in a single thread with non-blocking reads
+    fn linger(rejected: Receiver<TcpStream>) {
+        let mut lingering: Vec<(TcpStream, Instant)> = Vec::new();
+        let mut buffer = [0; 4096];
+        loop {
+            if lingering.is_empty() {
+                match rejected.recv() {
+                    Ok(stream) => lingering.push((stream, Instant::now() + LINGER_TIMEOUT)),
+                    Err(_) => return,
+                }
+            }
+            lingering.extend(rejected.try_iter().map(|stream| (stream, Instant::now() + LINGER_TIMEOUT)));
+            lingering.retain_mut(|(stream, deadline)| {
+                if Instant::now() >= *deadline {
+                    return false;
+                }
+                loop {
+                    match stream.read(&mut buffer) {
+                        Ok(0) => return false,
+                        Ok(_) => {}
+                        Err(e) => return e.kind() == io::ErrorKind::WouldBlock,
+                    }
+                }
+            });
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Answer with `status` and close the connection without reading the rest of the request
+    // Closing with unread bytes resets the connection and the client can lose the response,
+    // so the bytes it still sends are read and dropped until it closes or for LINGER_TIMEOUT
+    fn close_with(stream: &mut TcpStream, status: HttpStatus, message: &str) {
+        let response = HteaPot::with_connection(HteaPot::response_maker(status, message), false);
+        if let Err(e) = stream.write_all(response.as_bytes()) {
+            eprintln!("Error: {}", e);
+            return;
+        }
+        let _ = stream.shutdown(Shutdown::Write);
+        let deadline = Instant::now() + LINGER_TIMEOUT;
+        let mut buffer = [0; 4096];
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() || stream.set_read_timeout(Some(left)).is_err() {
+                break;
+            }
+            match stream.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+    }
+
+
+    // Escape every byte of `value` but the unreserved characters, to put it in a query
+    pub fn percent_encode(value: &str) -> String {
+        let mut encoded = String::new();
+        for byte in value.bytes() {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+                _ => encoded.push_str(&format!("%{:02X}", byte)),
+            }
+        }
+        encoded
+    }
+
+    // Undo the %XX escapes of a query, a % without two hex digits is kept as it is
+    fn percent_decode(value: &str) -> String {
+        let bytes = value.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            let escaped = bytes.get(i + 1..i + 3)
+                .filter(|_| bytes[i] == b'%')
+                .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+            match escaped {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 3;
+                }
+                None => {
+                    decoded.push(bytes[i]);
+                    i += 1;
+                }
+            }
+        }
+        String::from_utf8_lossy(&decoded).into_owned()
+    }
 
     // Create a response
     pub fn response_maker(status: HttpStatus, content: &str) -> String {
+        HteaPot::response_maker_with_headers(status, content, &[])
+    }
+
+    // Create a response with more headers than the Content-Length
+    pub fn response_maker_with_headers(status: HttpStatus, content: &str, headers: &[(&str, String)]) -> String {
         let status_text = status.to_string();
+        let mut head = String::new();
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
         let content_length = format!("Content-Length: {}", content.len());
-        let response = format!("HTTP/1.1 {} {}\r\n{}\r\n\r\n{}",status as u16, status_text,content_length ,content);
-        response
+        format!("HTTP/1.1 {} {}\r\n{}{}\r\n\r\n{}",status as u16, status_text, head, content_length ,content)
     }
 
     // Parse the request
-    pub fn request_parser(request: &str) -> HttpRequest {
-        let mut lines = request.lines();
-        let first_line = lines.next().unwrap();
+    // The head is everything up to the first empty line, the body is the rest as it is
+    // Returns why the request is not valid HTTP, to answer it with 400 Bad Request
+    pub fn request_parser(request: &str) -> Result<HttpRequest, &'static str> {
+        let (head, body) = match request.find("\r\n\r\n") {
+            Some(end) => (&request[..end], &request[end + 4..]),
+            None => match request.find("\n\n") {
+                Some(end) => (&request[..end], &request[end + 2..]),
+                None => (request, ""),
+            },
+        };
+        let mut lines = head.lines();
+        let first_line = lines.next().unwrap_or("");
         let mut words = first_line.split_whitespace();
-        let method = words.next().unwrap();
-        let mut path = words.next().unwrap().to_string();
+        let method = words.next().and_then(HttpMethod::from_str).ok_or("Invalid HTTP method")?;
+        let mut path = words.next().ok_or("Missing path")?.to_string();
+        let version = words.next().unwrap_or("HTTP/1.0");
         let mut headers: HashMap<String, String> = HashMap::new();
-        loop {
-            let line = lines.next().unwrap();
-            if line.is_empty() {
-                break;
-            }
-            let mut parts = line.split(": ");
-            let key = parts.next().unwrap().to_string();
-            let value = parts.next().unwrap();
-            headers.insert(key, value.to_string());
+        for line in lines {
+            // the space after the colon is optional, and the value can have colons too
+            let (key, value) = line.split_once(':').ok_or("Invalid header")?;
+            headers.insert(key.trim().to_string(), value.trim().to_string());
         }
-        let remaining_lines: Vec<&str>  = lines.collect();
-        let body = remaining_lines.join("");
-        let body = body.trim().trim_end();
-        //remove all traling zero bytes
-        let body = body.trim_matches(char::from(0));
         let mut args: HashMap<String, String> = HashMap::new();
         //remove http or https from the path
         if path.starts_with("http://") {
@@ -216,58 +520,218 @@ impl HteaPot {
             let query_parts: Vec<&str> = query.split('&').collect();
             for part in query_parts {
                 let mut parts = part.split('=');
-                let key = parts.next().unwrap().to_string();
-                let value = parts.next().unwrap_or("").to_string().replace("%22", "\"");
+                let key = HteaPot::percent_decode(parts.next().unwrap());
+                let value = HteaPot::percent_decode(parts.next().unwrap_or(""));
                 args.insert(key, value);
             }
         }
-        let body = body.trim_end().to_string();
-        let expected_size = headers.get("Content-Length").unwrap_or(&"-1".to_string()).parse::<i32>().unwrap();
-        let body_size = body.len();
-        println!("expected: {}\nrecived: {}",expected_size,body_size);
-        HttpRequest {
-            method: HttpMethod::from_str(method),
+        Ok(HttpRequest {
+            method,
             path: path.to_string(),
+            version: version.to_string(),
             args: args,
             headers: headers,
-            body: body.trim_end().to_string(),
+            body: body.to_string(),
+        })
+    }
+
+    // Read a line of at most `limit` bytes into `line`, returns how many bytes were read,
+    // 0 at the end of the connection, or the status for a longer line
+    // A limit used up without the end of the line is a line too long, also when it is 0
+    fn read_line_limited(reader: &mut BufReader<Timed>, line: &mut String, limit: usize, status: HttpStatus) -> Result<usize, ReadError> {
+        if limit == 0 {
+            return Err(ReadError::Status(status, status.to_string()));
         }
+        let read = reader.by_ref().take(limit as u64).read_line(line)?;
+        if read == limit && !line.ends_with('\n') {
+            return Err(ReadError::Status(status, status.to_string()));
+        }
+        Ok(read)
     }
 
-    // Handle the client when a request is received
-    fn handle_client(mut stream: TcpStream , action: impl Fn(HttpRequest) -> String ) {
-        let mut request_buffer: String = String::new();
+    // Read the next request of the connection, the head up to the empty line and the raw bytes
+    // of its body, the Content-Length bytes after the head or the chunks of Transfer-Encoding: chunked
+    // The bytes after it are kept in the reader, they are the next requests of a pipeline
+    // returns None when the client closed the connection
+    fn read_request(reader: &mut BufReader<Timed>, max_body_size: usize) -> Result<Option<(String, Vec<u8>)>, ReadError> {
+        let too_large = HttpStatus::RequestHeaderFieldsTooLarge;
+        let mut head = String::new();
+        // empty lines before a request are ignored
+        loop {
+            if Self::read_line_limited(reader, &mut head, MAX_HEAD_SIZE, too_large)? == 0 {
+                return Ok(None);
+            }
+            if !head.trim().is_empty() {
+                break;
+            }
+            head.clear();
+        }
+        let mut content_length = None;
+        let mut chunked = false;
+        let mut expect_continue = false;
         loop {
-            let mut buffer = [0; 1024];
-            stream.read(&mut buffer).unwrap_or_default();
-            if buffer[0] == 0 {break};
-            let partial_request_buffer = String::from_utf8_lossy(&buffer).to_string();
-            request_buffer.push_str(&partial_request_buffer);
-            if *buffer.last().unwrap() == 0 {break;}
+            let mut line = String::new();
+            if Self::read_line_limited(reader, &mut line, MAX_HEAD_SIZE - head.len(), too_large)? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                let (name, value) = (name.trim(), value.trim());
+                if name.eq_ignore_ascii_case("Content-Length") {
+                    content_length = Some(value.parse::<usize>().map_err(|_| ReadError::Status(HttpStatus::BadRequest, "Invalid Content-Length"))?);
+                } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+                    // the only coding known is chunked, and it must be the last one
+                    if !value.eq_ignore_ascii_case("chunked") {
+                        return Err(ReadError::Status(HttpStatus::NotImplemented, "Only the chunked transfer coding is supported"));
+                    }
+                    chunked = true;
+                } else if name.eq_ignore_ascii_case("Expect") && value.eq_ignore_ascii_case("100-continue") {
+                    expect_continue = true;
+                }
+            }
+            head.push_str(&line);
+            if line.trim().is_empty() {
+                break;
+            }
         }
-        
-        let request = Self::request_parser(&request_buffer);
-        println!("Received request: \n{} {}\n\n", request.method.to_str(), request.path);
-        //let response = Self::response_maker(HttpStatus::IAmATeapot, "Hello, World!");
-        let response = action(request);
-        let r = stream.write(response.as_bytes()); 
-        if r.is_err() {
-            eprintln!("Error: {}", r.err().unwrap());
+        if content_length.is_some_and(|length| length > max_body_size) {
+            return Err(ReadError::Status(HttpStatus::PayloadTooLarge, "Payload Too Large"));
         }
-        let r = stream.flush();
-        if r.is_err() {
-            eprintln!("Error: {}", r.err().unwrap());
+        // the client waits for this before sending the body
+        if expect_continue && (chunked || content_length.is_some_and(|length| length > 0)) {
+            let stream = &mut reader.get_mut().stream;
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            stream.flush()?;
         }
+        let body = if chunked {
+            Self::read_chunks(reader, max_body_size)?
+        } else {
+            let mut body = vec![0; content_length.unwrap_or(0)];
+            reader.read_exact(&mut body)?;
+            body
+        };
+        Ok(Some((head, body)))
     }
-}
 
+    // Read a chunked body: every chunk is its size in hexadecimal on a line, then its bytes,
+    // up to a chunk of size 0 followed by the trailer lines, which are ignored
+    fn read_chunks(reader: &mut BufReader<Timed>, max_body_size: usize) -> Result<Vec<u8>, ReadError> {
+        let mut body = Vec::new();
+        loop {
+            let mut line = String::new();
+            if Self::read_line_limited(reader, &mut line, MAX_HEAD_SIZE, HttpStatus::BadRequest)? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            // the size can be followed by extensions after a ;
+            let size = line.split(';').next().unwrap_or("").trim();
+            let size = usize::from_str_radix(size, 16).map_err(|_| ReadError::Status(HttpStatus::BadRequest, "Invalid chunk size"))?;
+            if size == 0 {
+                break;
+            }
+            if body.len() + size > max_body_size {
+                return Err(ReadError::Status(HttpStatus::PayloadTooLarge, "Payload Too Large"));
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            reader.read_exact(&mut body[start..])?;
+            let mut end = [0; 2];
+            reader.read_exact(&mut end)?;
+            if &end != b"\r\n" {
+                return Err(ReadError::Status(HttpStatus::BadRequest, "Invalid chunk"));
+            }
+        }
+        loop {
+            let mut line = String::new();
+            if Self::read_line_limited(reader, &mut line, MAX_HEAD_SIZE, HttpStatus::BadRequest)? == 0 || line.trim().is_empty() {
+                break;
+            }
+        }
+        Ok(body)
+    }
+
+    // Add the Connection header after the status line of a response
+    fn with_connection(response: String, keep_alive: bool) -> String {
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        match response.split_once("\r\n") {
+            Some((status, rest)) => format!("{}\r\nConnection: {}\r\n{}", status, connection, rest),
+            None => response,
+        }
+    }
+
+    // Handle the requests of a connection one after the other, until the client closes it,
+    // asks to close it, sends nothing for `idle_timeout` or takes longer than `request_timeout`
+    // to send a request
+    fn handle_client(stream: TcpStream, idle_timeout: Duration, request_timeout: Duration, max_body_size: usize, slot: &Slot, action: impl Fn(HttpRequest) -> String ) {
+        let mut reader = match stream.try_clone() {
+            Ok(stream) => BufReader::new(Timed { stream, deadline: Instant::now() + request_timeout }),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return;
+            }
+        };
+        let mut stream = stream;
+        loop {
+            let (head, body) = match Self::read_request(&mut reader, max_body_size) {
+                Ok(Some(request)) => request,
+                // closed by the client or idle for too long
+                Ok(None) => break,
+                Err(ReadError::Io(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+                Err(ReadError::Io(e)) => {
+                    eprintln!("Error: {}", e);
+                    break;
+                }
+                // the rest of the request can't be told apart from the next one, the connection is closed
+                Err(ReadError::Status(status, message)) => {
+                    Self::close_with(&mut stream, status, message);
+                    break;
+                }
+            };
+            let mut request = match Self::request_parser(&head) {
+                Ok(request) => request,
+                Err(message) => {
+                    Self::close_with(&mut stream, HttpStatus::BadRequest, message);
+                    break;
+                }
+            };
+            request.body = match String::from_utf8(body) {
+                Ok(body) => body,
+                Err(_) => {
+                    Self::close_with(&mut stream, HttpStatus::BadRequest, "The body must be UTF-8");
+                    break;
+                }
+            };
+            println!("Received request: \n{} {}\n\n", request.method.to_str(), request.path);
+            let keep_alive = request.keep_alive() && !idle_timeout.is_zero() && !slot.crowded();
+            //let response = Self::response_maker(HttpStatus::IAmATeapot, "Hello, World!");
+            let response = Self::with_connection(action(request), keep_alive);
+            let r = stream.write_all(response.as_bytes());
+            if r.is_err() {
+                eprintln!("Error: {}", r.err().unwrap());
+                break;
+            }
+            let r = stream.flush();
+            if r.is_err() {
+                eprintln!("Error: {}", r.err().unwrap());
+                break;
+            }
+            if !keep_alive {
+                break;
+            }
+            // the next request can take `idle_timeout` to start, then `request_timeout` to arrive whole
+            reader.get_mut().deadline = Instant::now() + idle_timeout;
+            if !matches!(reader.fill_buf(), Ok(buffer) if !buffer.is_empty()) {
+                break;
+            }
+            reader.get_mut().deadline = Instant::now() + request_timeout;
+        }
+    }
+}
 
 #[cfg(test)]
 
 #[test]
 fn test_http_parser() {
     let request = "GET / HTTP/1.1\r\nHost: localhost:8080\r\nUser-Agent: curl/7.68.0\r\nAccept: */*\r\n\r\n";
-    let parsed_request = HteaPot::request_parser(request);
+    let parsed_request = HteaPot::request_parser(request).unwrap();
     assert_eq!(parsed_request.method, HttpMethod::GET);
     assert_eq!(parsed_request.path, "/");
     assert_eq!(parsed_request.args.len(), 0);
@@ -275,6 +739,29 @@ fn test_http_parser() {
     assert_eq!(parsed_request.body, "");
 }
 
+#[test]
+fn test_http_parser_body() {
+    let request = "POST /users/_bulk HTTP/1.1\r\nContent-Length: 24\r\n\r\n{\"insert\": {}}\n\n  {\"a\": 1}";
+    let parsed_request = HteaPot::request_parser(request).unwrap();
+    assert_eq!(parsed_request.body, "{\"insert\": {}}\n\n  {\"a\": 1}");
+    let parsed_request = HteaPot::request_parser("GET / HTTP/1.1\r\nContent-Length:0\r\nReferer: http://localhost:8080/\r\n\r\n").unwrap();
+    assert_eq!(parsed_request.header("content-length"), Some("0"));
+    assert_eq!(parsed_request.header("Referer"), Some("http://localhost:8080/"));
+    assert!(HteaPot::request_parser("GET / HTTP/1.1\r\nHost\r\n\r\n").is_err());
+    assert!(HteaPot::request_parser("BREW / HTTP/1.1\r\n\r\n").is_err());
+}
+
+#[test]
+fn test_query_escapes() {
+    let value = "a&b=#c d>e,\"f\"<ñ%";
+    let encoded = HteaPot::percent_encode(value);
+    assert_eq!(encoded, "a%26b%3D%23c%20d%3Ee%2C%22f%22%3C%C3%B1%25");
+    let parsed_request = HteaPot::request_parser(&format!("GET /users/all?name={}&sort=age,-name&x=%zz HTTP/1.1\r\n\r\n", encoded)).unwrap();
+    assert_eq!(parsed_request.args["name"], value);
+    assert_eq!(parsed_request.args["sort"], "age,-name");
+    assert_eq!(parsed_request.args["x"], "%zz");
+}
+
 #[test]
 fn test_http_response_maker() {
     let response = HteaPot::response_maker(HttpStatus::IAmATeapot, "Hello, World!");
@@ -282,3 +769,180 @@ fn test_http_response_maker() {
     assert_eq!(response, expected_response);
 }
 
+#[test]
+fn test_overload_rejected() {
+    use std::sync::mpsc;
+    use std::thread;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let (release, released) = mpsc::channel::<()>();
+    let released = Mutex::new(released);
+    let pool = PoolConfig { workers: 1, queue_depth: 0, overload: Overload::Reject };
+    thread::spawn(move || {
+        HteaPot::new("127.0.0.1", 0).with_pool(pool).serve(listener, move |_| {
+            released.lock().unwrap().recv().unwrap();
+            HteaPot::response_maker(HttpStatus::OK, "OK")
+        })
+    });
+    let send = || {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        stream
+    };
+    let read = |mut stream: TcpStream| {
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    // the connections are accepted in order, the only worker is busy with the first one
+    let first = send();
+    assert!(read(send()).starts_with("HTTP/1.1 503 Service Unavailable"));
+    // the rejected request is read before closing, the client gets the 503 and not a reset
+    let mut stream = TcpStream::connect(address).unwrap();
+    let body = "x".repeat(64 * 1024);
+    stream.write_all(format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).as_bytes()).unwrap();
+    assert!(read(stream).starts_with("HTTP/1.1 503 Service Unavailable"));
+    // rejected clients that keep their connections open don't delay the next ones
+    let held: Vec<TcpStream> = (0..5).map(|_| send()).collect();
+    let start = std::time::Instant::now();
+    assert!(read(send()).starts_with("HTTP/1.1 503 Service Unavailable"));
+    assert!(start.elapsed() < LINGER_TIMEOUT);
+    drop(held);
+    release.send(()).unwrap();
+    assert!(read(first).starts_with("HTTP/1.1 200 OK"));
+    assert_eq!(Overload::parse("wait"), Some(Overload::Wait));
+    assert_eq!(Overload::parse("queue"), None);
+}
+
+#[test]
+fn test_keep_alive_and_pipelining() {
+    use std::thread;
+    use std::time::Instant;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        HteaPot::new("127.0.0.1", 0).with_idle_timeout(Duration::from_millis(300)).serve(listener, |request| {
+            HteaPot::response_maker(HttpStatus::OK, &format!("{} {}", request.path, request.body))
+        })
+    });
+    let read = |mut stream: TcpStream| {
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    // three requests in a single write, the last one closes the connection
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(concat!(
+        "POST /a HTTP/1.1\r\nContent-Length: 9\r\n\r\n{\"a\": 1}\n",
+        "GET /b HTTP/1.1\r\n\r\n",
+        "GET /c HTTP/1.1\r\nConnection: close\r\n\r\n",
+    ).as_bytes()).unwrap();
+    let response = read(stream);
+    let responses: Vec<&str> = response.split("HTTP/1.1 200 OK\r\n").skip(1).collect();
+    assert_eq!(responses.len(), 3);
+    assert!(responses[0].starts_with("Connection: keep-alive\r\n") && responses[0].ends_with("/a {\"a\": 1}\n"));
+    assert!(responses[1].starts_with("Connection: keep-alive\r\n") && responses[1].ends_with("/b "));
+    assert!(responses[2].starts_with("Connection: close\r\n") && responses[2].ends_with("/c "));
+
+    // HTTP/1.0 closes unless asked, an idle connection is closed after the timeout
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(b"GET /d HTTP/1.0\r\n\r\n").unwrap();
+    assert!(read(stream).contains("Connection: close"));
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(b"GET /e HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").unwrap();
+    let start = Instant::now();
+    assert!(read(stream).contains("Connection: keep-alive"));
+    assert!(start.elapsed() >= Duration::from_millis(300));
+}
+
+#[test]
+fn test_request_timeout() {
+    use std::thread;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let teapot = HteaPot::new("127.0.0.1", 0).with_idle_timeout(Duration::from_secs(60)).with_request_timeout(Duration::from_millis(300));
+        teapot.serve(listener, |_| HteaPot::response_maker(HttpStatus::OK, "OK"))
+    });
+
+    // a byte every 100 ms never ends the idle timeout, the request timeout closes the connection
+    let start = Instant::now();
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    stream.read_exact(&mut [0; 17]).unwrap();
+    let closed = (0..20).any(|_| {
+        thread::sleep(Duration::from_millis(100));
+        stream.write_all(b"G").is_err()
+    });
+    assert!(closed && start.elapsed() < Duration::from_secs(1));
+
+    // a connection that never sends a request is closed too
+    let start = Instant::now();
+    let mut stream = TcpStream::connect(address).unwrap();
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+    assert!(start.elapsed() < Duration::from_secs(1));
+    let teapot = HteaPot::new("127.0.0.1", 0).with_idle_timeout(Duration::ZERO).with_request_timeout(Duration::from_millis(300));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || teapot.serve(listener, |_| HteaPot::response_maker(HttpStatus::OK, "OK")));
+    let mut stream = TcpStream::connect(address).unwrap();
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+}
+
+#[test]
+fn test_request_bodies() {
+    use std::thread;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        HteaPot::new("127.0.0.1", 0).with_max_body_size(1200).serve(listener, |request| {
+            HteaPot::response_maker(HttpStatus::OK, &request.body)
+        })
+    });
+    let send = |request: &[u8]| {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    // a body of exactly 1024 bytes with newlines, and a chunked one
+    let body = "line\n".repeat(204) + "\r\n\r\n";
+    let request = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+    let chunked = "PUT / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n5\r\nline\n\r\n3;x=y\r\nend\r\n0\r\nTrailer: z\r\n\r\n";
+    let response = send((request + chunked).as_bytes());
+    let responses: Vec<&str> = response.split("HTTP/1.1 200 OK\r\n").skip(1).collect();
+    assert_eq!(body.len(), 1024);
+    assert!(responses[0].ends_with(&format!("\r\n\r\n{}", body)));
+    assert!(responses[1].ends_with("\r\n\r\nline\nend"));
+
+    // the body is too large, with Expect the client never sends it
+    let response = send(b"POST / HTTP/1.1\r\nContent-Length: 1201\r\nExpect: 100-continue\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\nConnection: close\r\n"));
+    let response = send(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4b1\r\n");
+    assert!(response.starts_with("HTTP/1.1 413 Payload Too Large"));
+    let response = send(b"POST / HTTP/1.1\r\nContent-Length: 2\r\nExpect: 100-continue\r\nConnection: close\r\n\r\n{}");
+    assert!(response.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n") && response.ends_with("{}"));
+    let response = send(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 501 Not Implemented"));
+
+    // no space after the colon, a header without colon, and a head too large
+    let response = send(b"POST / HTTP/1.1\r\nContent-Length:5\r\nConnection: close\r\n\r\nhello");
+    assert!(response.starts_with("HTTP/1.1 200 OK") && response.ends_with("hello"));
+    let response = send(b"GET / HTTP/1.1\r\nHost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+    let response = send(format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "a".repeat(MAX_HEAD_SIZE)).as_bytes());
+    assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large"));
+
+    // a head of exactly the limit, and the header lines alone filling it
+    let start = "GET / HTTP/1.1\r\nConnection: close\r\nCookie: ";
+    let cookie = "a".repeat(MAX_HEAD_SIZE - start.len() - 4);
+    let response = send(format!("{}{}\r\n\r\n", start, cookie).as_bytes());
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    for extra in ["a", "aa"] {
+        let response = send(format!("{}{}{}\r\n\r\n", start, cookie, extra).as_bytes());
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large"), "{}", response);
+    }
+}
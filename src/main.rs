@@ -1,15 +1,44 @@
-use std::sync::Mutex;
+use std::sync::Arc;
 mod engine;
 mod memodb;
 mod hteapot;
 use engine::Engine;
 use std::env;
-use hteapot::HteaPot;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::Duration;
+use hteapot::{HteaPot, Overload, PoolConfig};
+use memodb::wal::SyncPolicy;
 
 const DEFAULT_PORT: u16 = 8080;
+const DEFAULT_SNAPSHOT_PATH: &str = "memodb.snapshot";
+// seconds between two periodic snapshots
+const DEFAULT_SNAPSHOT_INTERVAL: u64 = 60;
+const DEFAULT_WAL_PATH: &str = "memodb.wal";
+// "always", "never" or the milliseconds between two flushes of the write-ahead log
+const DEFAULT_WAL_SYNC: &str = "always";
+// threads handling the connections
+const DEFAULT_WORKERS: usize = 16;
+// connections accepted waiting for a free worker
+const DEFAULT_QUEUE_DEPTH: usize = 128;
+// "reject" answers 503 when the queue is full, "wait" stops accepting until a worker is free
+const DEFAULT_OVERLOAD: &str = "reject";
+// seconds a connection is kept open waiting for its next request, 0 closes it after each response
+const DEFAULT_IDLE_TIMEOUT: u64 = 5;
+// seconds a client has to send a whole request, also when idle connections are not kept
+const DEFAULT_REQUEST_TIMEOUT: u64 = 30;
+// bytes of the largest request body accepted
+const DEFAULT_MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+fn save_snapshot(engine: &Engine) {
+    if let Err(e) = engine.save() {
+        eprintln!("Error saving snapshot: {}", e);
+    }
+}
 
 fn main() {
-    
+
     //let args: Vec<String> = env::args().collect();
     let addr: String = String::from("0.0.0.0");
     //let port = args.get(2);
@@ -17,13 +46,117 @@ fn main() {
             Ok(val) => val,
             Err(_) => DEFAULT_PORT.to_string(),
     };
-    let teapot = HteaPot::new(&addr, port.parse().unwrap());
-    let engine = Mutex::new(Engine::new());
-    engine.lock().unwrap().init_mock_data();
+    let snapshot_path = match env::var("SNAPSHOT_PATH") {
+            Ok(val) => PathBuf::from(val),
+            Err(_) => PathBuf::from(DEFAULT_SNAPSHOT_PATH),
+    };
+    let snapshot_interval = env::var("SNAPSHOT_INTERVAL").unwrap_or(DEFAULT_SNAPSHOT_INTERVAL.to_string());
+    let snapshot_interval = match snapshot_interval.parse::<u64>() {
+        Ok(snapshot_interval) if snapshot_interval > 0 => snapshot_interval,
+        _ => {
+            eprintln!("Invalid SNAPSHOT_INTERVAL {}, expected a number of seconds greater than 0", snapshot_interval);
+            process::exit(1);
+        }
+    };
+    let wal_path = match env::var("WAL_PATH") {
+            Ok(val) => PathBuf::from(val),
+            Err(_) => PathBuf::from(DEFAULT_WAL_PATH),
+    };
+    let wal_sync = env::var("WAL_SYNC").unwrap_or(DEFAULT_WAL_SYNC.to_string());
+    let wal_sync = match SyncPolicy::parse(&wal_sync) {
+        Some(policy) => policy,
+        None => {
+            eprintln!("Invalid WAL_SYNC {}, expected always, never or a number of milliseconds", wal_sync);
+            process::exit(1);
+        }
+    };
+    let workers = env::var("WORKERS").unwrap_or(DEFAULT_WORKERS.to_string());
+    let workers = match workers.parse::<usize>() {
+        Ok(workers) if workers > 0 => workers,
+        _ => {
+            eprintln!("Invalid WORKERS {}, expected a number of threads greater than 0", workers);
+            process::exit(1);
+        }
+    };
+    let queue_depth = env::var("QUEUE_DEPTH").unwrap_or(DEFAULT_QUEUE_DEPTH.to_string());
+    let queue_depth = match queue_depth.parse::<usize>() {
+        Ok(queue_depth) => queue_depth,
+        Err(_) => {
+            eprintln!("Invalid QUEUE_DEPTH {}, expected a number of connections", queue_depth);
+            process::exit(1);
+        }
+    };
+    let overload = env::var("OVERLOAD").unwrap_or(DEFAULT_OVERLOAD.to_string());
+    let overload = match Overload::parse(&overload) {
+        Some(overload) => overload,
+        None => {
+            eprintln!("Invalid OVERLOAD {}, expected reject or wait", overload);
+            process::exit(1);
+        }
+    };
+    let idle_timeout = env::var("IDLE_TIMEOUT").unwrap_or(DEFAULT_IDLE_TIMEOUT.to_string());
+    let idle_timeout = match idle_timeout.parse::<u64>() {
+        Ok(idle_timeout) => idle_timeout,
+        Err(_) => {
+            eprintln!("Invalid IDLE_TIMEOUT {}, expected a number of seconds", idle_timeout);
+            process::exit(1);
+        }
+    };
+    let request_timeout = env::var("REQUEST_TIMEOUT").unwrap_or(DEFAULT_REQUEST_TIMEOUT.to_string());
+    let request_timeout = match request_timeout.parse::<u64>() {
+        Ok(request_timeout) if request_timeout > 0 => request_timeout,
+        _ => {
+            eprintln!("Invalid REQUEST_TIMEOUT {}, expected a number of seconds greater than 0", request_timeout);
+            process::exit(1);
+        }
+    };
+    let max_body_size = env::var("MAX_BODY_SIZE").unwrap_or(DEFAULT_MAX_BODY_SIZE.to_string());
+    let max_body_size = match max_body_size.parse::<usize>() {
+        Ok(max_body_size) => max_body_size,
+        Err(_) => {
+            eprintln!("Invalid MAX_BODY_SIZE {}, expected a number of bytes", max_body_size);
+            process::exit(1);
+        }
+    };
+    let pool = PoolConfig { workers, queue_depth, overload };
+    let teapot = HteaPot::new(&addr, port.parse().unwrap())
+        .with_pool(pool)
+        .with_idle_timeout(Duration::from_secs(idle_timeout))
+        .with_request_timeout(Duration::from_secs(request_timeout))
+        .with_max_body_size(max_body_size);
+    let engine = match Engine::open(&snapshot_path, &wal_path, wal_sync) {
+        Ok(engine) => Arc::new(engine),
+        Err(e) => {
+            // never start over a snapshot we can't read, the next save would overwrite it
+            eprintln!("Error loading snapshot {}: {}", snapshot_path.display(), e);
+            process::exit(1);
+        }
+    };
+    println!("Loaded snapshot {} and write-ahead log {}", snapshot_path.display(), wal_path.display());
+
+    let periodic_engine = engine.clone();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(snapshot_interval));
+        save_snapshot(&periodic_engine);
+    });
+
+    #[cfg(not(target_os = "wasi"))]
+    {
+        let shutdown_engine = engine.clone();
+        let r = ctrlc::set_handler(move || {
+            println!("Shutting down, saving snapshot...");
+            save_snapshot(&shutdown_engine);
+            process::exit(0);
+        });
+        if let Err(e) = r {
+            eprintln!("Error: {}", e);
+        }
+    }
+
     println!("Starting server...");
     println!("Listening on {}:{}...", addr, port);
+    // the engine locks the collections a request uses, requests run concurrently
     teapot.listen( move|request| {
-        let mut engine = engine.lock().unwrap();
         engine.process(request)
     });
 }
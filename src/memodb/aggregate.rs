@@ -0,0 +1,477 @@
+// The aggregate module runs aggregation pipelines over the documents of a collection
+// A pipeline is a JSON array of stages, each stage works on the documents returned by the previous one
+//
+//   [{"$match": {"status": "paid"}},
+//    {"$group": {"_id": "$customer", "total": {"$sum": "$amount"}, "orders": {"$count": {}}}},
+//    {"$sort": {"total": -1}},
+//    {"$limit": 10}]
+//
+// Stages: $match, $project, $group, $sort, $limit, $skip, $unwind and $count
+// Accumulators of $group: $count, $sum, $avg, $min, $max and $push
+// A text starting with $ is the value at that path of the document, "$address.city",
+// any other value is a literal
+
+use std::collections::BTreeMap;
+use serde_json::{Map, Value};
+use super::collection::{Collection, Document};
+use super::data_type::DataType;
+use super::index::{IndexField, IndexKey, KeyPart};
+use super::path::{self, Projection};
+use super::query::Filter;
+use super::sort::Sort;
+
+const ID: &str = "ID";
+const GROUP_ID: &str = "_id";
+
+#[derive(Debug)]
+enum Expression {
+    Path(String),
+    Literal(DataType),
+    Document(Vec<(String, Expression)>),
+}
+
+impl Expression {
+    fn parse(value: &Value) -> Result<Expression, String> {
+        match value {
+            Value::String(text) if text.starts_with('$') => match &text[1..] {
+                "" => Err("An expression path can't be empty".to_string()),
+                path => Ok(Expression::Path(path.to_string())),
+            },
+            Value::Object(object) => {
+                if let Some(key) = object.keys().find(|key| key.starts_with('$')) {
+                    return Err(format!("Unknown expression operator {}", key));
+                }
+                let fields = object.iter()
+                    .map(|(key, value)| Ok((key.clone(), Expression::parse(value)?)))
+                    .collect::<Result<_, String>>()?;
+                Ok(Expression::Document(fields))
+            }
+            _ => Ok(Expression::Literal(DataType::from_value(value))),
+        }
+    }
+
+    // None when the path is missing
+    fn eval(&self, document: &Document) -> Option<DataType> {
+        match self {
+            Expression::Path(path) => {
+                let mut values = path::values(document, path);
+                match values.len() {
+                    0 => None,
+                    1 => values.pop().cloned(),
+                    _ => Some(DataType::Array(values.into_iter().cloned().collect())),
+                }
+            }
+            Expression::Literal(value) => Some(value.clone()),
+            Expression::Document(fields) => Some(DataType::Document(fields.iter()
+                .filter_map(|(key, expression)| Some((key.clone(), expression.eval(document)?)))
+                .collect())),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Accumulator {
+    Count,
+    Sum(Expression),
+    Avg(Expression),
+    Min(Expression),
+    Max(Expression),
+    Push(Expression),
+}
+
+impl Accumulator {
+    fn parse(field: &str, value: &Value) -> Result<Accumulator, String> {
+        let (operator, argument) = match value.as_object() {
+            Some(object) if object.len() == 1 => object.iter().next().unwrap(),
+            _ => return Err(format!("The group field {} must be an object with one accumulator", field)),
+        };
+        let expression = || Expression::parse(argument);
+        match operator.as_str() {
+            "$count" => Ok(Accumulator::Count),
+            "$sum" => Ok(Accumulator::Sum(expression()?)),
+            "$avg" => Ok(Accumulator::Avg(expression()?)),
+            "$min" => Ok(Accumulator::Min(expression()?)),
+            "$max" => Ok(Accumulator::Max(expression()?)),
+            "$push" => Ok(Accumulator::Push(expression()?)),
+            _ => Err(format!("Unknown accumulator {}", operator)),
+        }
+    }
+
+    fn start(&self) -> State {
+        match self {
+            Accumulator::Count => State::Count(0),
+            Accumulator::Sum(_) => State::Sum { int: 0, float: None },
+            Accumulator::Avg(_) => State::Avg { sum: 0.0, count: 0 },
+            Accumulator::Min(_) | Accumulator::Max(_) => State::Extreme(None),
+            Accumulator::Push(_) => State::Push(Vec::new()),
+        }
+    }
+}
+
+// Running value of an accumulator in a group
+enum State {
+    Count(i64),
+    // integers are added exactly until a float is found or they overflow
+    Sum { int: i64, float: Option<f64> },
+    Avg { sum: f64, count: u64 },
+    Extreme(Option<DataType>),
+    Push(Vec<DataType>),
+}
+
+impl State {
+    fn add(&mut self, accumulator: &Accumulator, document: &Document) {
+        match (self, accumulator) {
+            (State::Count(count), _) => *count += 1,
+            (State::Sum { int, float }, Accumulator::Sum(expression)) => {
+                let value = expression.eval(document);
+                match value.as_ref().and_then(DataType::as_integer) {
+                    Some(number) if float.is_none() => match int.checked_add(number) {
+                        Some(sum) => *int = sum,
+                        None => *float = Some(*int as f64 + number as f64),
+                    },
+                    _ => {
+                        if let Some(number) = value.as_ref().and_then(DataType::as_float) {
+                            *float = Some(float.unwrap_or(*int as f64) + number);
+                        }
+                    }
+                }
+            }
+            (State::Avg { sum, count }, Accumulator::Avg(expression)) => {
+                if let Some(number) = expression.eval(document).as_ref().and_then(DataType::as_float) {
+                    *sum += number;
+                    *count += 1;
+                }
+            }
+            (State::Extreme(current), Accumulator::Min(expression) | Accumulator::Max(expression)) => {
+                let value = match expression.eval(document) {
+                    None | Some(DataType::Null) => return,
+                    Some(value) => value,
+                };
+                let order = value.total_cmp(current.as_ref().unwrap_or(&value));
+                let better = match accumulator {
+                    Accumulator::Min(_) => order.is_lt(),
+                    _ => order.is_gt(),
+                };
+                if current.is_none() || better {
+                    *current = Some(value);
+                }
+            }
+            (State::Push(values), Accumulator::Push(expression)) => {
+                if let Some(value) = expression.eval(document) {
+                    values.push(value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn result(self) -> DataType {
+        match self {
+            State::Count(count) => DataType::integer(count),
+            State::Sum { int, float: None } => DataType::integer(int),
+            State::Sum { float: Some(float), .. } => DataType::Float(float),
+            State::Avg { count: 0, .. } => DataType::Null,
+            State::Avg { sum, count } => DataType::Float(sum / count as f64),
+            State::Extreme(value) => value.unwrap_or(DataType::Null),
+            State::Push(values) => DataType::Array(values),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Stage {
+    Match(Filter),
+    // computed fields are added to the projected document
+    Project { projection: Projection, computed: Vec<(String, Expression)> },
+    Group { id: Expression, fields: Vec<(String, Accumulator)> },
+    Sort(Sort),
+    Limit(usize),
+    Skip(usize),
+    Unwind { path: String, preserve: bool },
+    Count(String),
+}
+
+fn count(stage: &str, value: &Value) -> Result<usize, String> {
+    value.as_u64()
+        .map(|count| count as usize)
+        .ok_or(format!("{} must be a positive integer", stage))
+}
+
+fn object<'a>(stage: &str, value: &'a Value) -> Result<&'a Map<String, Value>, String> {
+    value.as_object().ok_or(format!("{} must be an object", stage))
+}
+
+impl Stage {
+    fn parse(value: &Value) -> Result<Stage, String> {
+        let (name, value) = match value.as_object() {
+            Some(stage) if stage.len() == 1 => stage.iter().next().unwrap(),
+            _ => return Err("Every stage must be an object with one key".to_string()),
+        };
+        match name.as_str() {
+            "$match" => Ok(Stage::Match(Filter::from_value(value)?)),
+            "$project" => Stage::parse_project(object(name, value)?),
+            "$group" => Stage::parse_group(object(name, value)?),
+            "$sort" => {
+                let fields = object(name, value)?.iter().map(|(field, order)| match order.as_i64() {
+                    Some(1) => Ok(IndexField { name: field.clone(), descending: false }),
+                    Some(-1) => Ok(IndexField { name: field.clone(), descending: true }),
+                    _ => Err(format!("The order of {} must be 1 or -1", field)),
+                }).collect::<Result<Vec<IndexField>, String>>()?;
+                if fields.is_empty() {
+                    return Err("$sort can't be empty".to_string());
+                }
+                Ok(Stage::Sort(Sort { fields }))
+            }
+            "$limit" => Ok(Stage::Limit(count(name, value)?)),
+            "$skip" => Ok(Stage::Skip(count(name, value)?)),
+            "$unwind" => {
+                let (path, preserve) = match value {
+                    Value::String(path) => (path.as_str(), false),
+                    Value::Object(options) => (
+                        options.get("path").and_then(Value::as_str).unwrap_or_default(),
+                        options.get("preserveNullAndEmptyArrays").and_then(Value::as_bool).unwrap_or(false),
+                    ),
+                    _ => ("", false),
+                };
+                match path.strip_prefix('$') {
+                    Some(path) if !path.is_empty() => Ok(Stage::Unwind { path: path.to_string(), preserve }),
+                    _ => Err("$unwind needs a path like \"$tags\"".to_string()),
+                }
+            }
+            "$count" => match value.as_str() {
+                Some(field) if !field.is_empty() && !field.starts_with('$') => Ok(Stage::Count(field.to_string())),
+                _ => Err("$count needs the name of the field".to_string()),
+            },
+            _ => Err(format!("Unknown stage {}", name)),
+        }
+    }
+
+    // 1 or true includes a field, 0 or false excludes it and any other value computes it
+    fn parse_project(fields: &Map<String, Value>) -> Result<Stage, String> {
+        let mut included = Vec::new();
+        let mut excluded = Vec::new();
+        let mut computed = Vec::new();
+        for (field, value) in fields {
+            let include = match value {
+                Value::Bool(include) => *include,
+                Value::Number(number) => number.as_f64() != Some(0.0),
+                _ => {
+                    computed.push((field.clone(), Expression::parse(value)?));
+                    continue;
+                }
+            };
+            if include { included.push(field.clone()) } else { excluded.push(field.clone()) }
+        }
+        if included.is_empty() && computed.is_empty() {
+            if excluded.is_empty() {
+                return Err("$project can't be empty".to_string());
+            }
+            return Ok(Stage::Project { projection: Projection::Exclude(excluded), computed });
+        }
+        let id = match excluded.as_slice() {
+            [] => true,
+            [id] if id == ID => false,
+            _ => return Err("$project can't mix included and excluded fields".to_string()),
+        };
+        Ok(Stage::Project { projection: Projection::Include { paths: included, id }, computed })
+    }
+
+    fn parse_group(fields: &Map<String, Value>) -> Result<Stage, String> {
+        let id = match fields.get(GROUP_ID) {
+            Some(id) => Expression::parse(id)?,
+            None => return Err("$group needs an _id".to_string()),
+        };
+        let fields = fields.iter()
+            .filter(|(field, _)| *field != GROUP_ID)
+            .map(|(field, value)| Ok((field.clone(), Accumulator::parse(field, value)?)))
+            .collect::<Result<_, String>>()?;
+        Ok(Stage::Group { id, fields })
+    }
+
+    fn run(&self, documents: Vec<Document>) -> Vec<Document> {
+        match self {
+            Stage::Match(filter) => documents.into_iter().filter(|document| filter.matches(document)).collect(),
+            Stage::Project { projection, computed } => documents.iter().map(|document| {
+                let mut result = projection.apply(document);
+                for (field, expression) in computed {
+                    if let Some(value) = expression.eval(document) {
+                        path::set(&mut result, field, value);
+                    }
+                }
+                result
+            }).collect(),
+            Stage::Group { id, fields } => Stage::group(documents, id, fields),
+            Stage::Sort(sort) => {
+                let mut documents = documents;
+                documents.sort_by_cached_key(|document| sort.key(document));
+                documents
+            }
+            Stage::Limit(limit) => documents.into_iter().take(*limit).collect(),
+            Stage::Skip(skip) => documents.into_iter().skip(*skip).collect(),
+            Stage::Unwind { path, preserve } => {
+                let mut result = Vec::new();
+                for document in documents {
+                    match path::values(&document, path).as_slice() {
+                        [DataType::Array(items)] if !items.is_empty() => {
+                            for item in items {
+                                let mut copy = document.clone();
+                                path::set(&mut copy, path, item.clone());
+                                result.push(copy);
+                            }
+                        }
+                        // a value that is not an array is kept as it is
+                        [value] if !matches!(value, DataType::Array(_) | DataType::Null) => result.push(document.clone()),
+                        _ if *preserve => result.push(document.clone()),
+                        _ => {}
+                    }
+                }
+                result
+            }
+            Stage::Count(field) => match documents.len() {
+                0 => Vec::new(),
+                count => vec![Document::from([(field.clone(), DataType::integer(count as i64))])],
+            },
+        }
+    }
+
+    // Groups are returned in the order they are first found
+    fn group(documents: Vec<Document>, id: &Expression, fields: &[(String, Accumulator)]) -> Vec<Document> {
+        let mut positions: BTreeMap<IndexKey, usize> = BTreeMap::new();
+        let mut groups: Vec<(DataType, Vec<State>)> = Vec::new();
+        for document in &documents {
+            let value = id.eval(document).unwrap_or(DataType::Null);
+            let key = IndexKey(vec![KeyPart { value: Some(value.clone()), descending: false }]);
+            let position = *positions.entry(key).or_insert_with(|| {
+                groups.push((value, fields.iter().map(|(_, accumulator)| accumulator.start()).collect()));
+                groups.len() - 1
+            });
+            for (state, (_, accumulator)) in groups[position].1.iter_mut().zip(fields) {
+                state.add(accumulator, document);
+            }
+        }
+        groups.into_iter().map(|(id, states)| {
+            let mut result = Document::from([(GROUP_ID.to_string(), id)]);
+            for (state, (field, _)) in states.into_iter().zip(fields) {
+                result.insert(field.clone(), state.result());
+            }
+            result
+        }).collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct Pipeline {
+    stages: Vec<Stage>,
+}
+
+impl Pipeline {
+    pub fn from_json(json: &str) -> Result<Pipeline, String> {
+        let value: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        Pipeline::from_value(&value)
+    }
+
+    pub fn from_value(value: &Value) -> Result<Pipeline, String> {
+        match value {
+            Value::Array(stages) => Ok(Pipeline {
+                stages: stages.iter().map(Stage::parse).collect::<Result<_, String>>()?,
+            }),
+            _ => Err("A pipeline must be a JSON array of stages".to_string()),
+        }
+    }
+
+    pub fn run(&self, collection: &Collection) -> Vec<Document> {
+        // a first $match can use the indexes of the collection
+        let (documents, stages) = match self.stages.split_first() {
+            Some((Stage::Match(filter), rest)) => (collection.query(filter), rest),
+            _ => (collection.query(&Filter::And(Vec::new())), self.stages.as_slice()),
+        };
+        let documents = documents.into_iter().cloned().collect();
+        stages.iter().fold(documents, |documents, stage| stage.run(documents))
+    }
+}
+
+
+//TEST
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use crate::memodb::collection::{Collection, DocumentJson, Document};
+    use super::Pipeline;
+
+    fn orders() -> Collection {
+        let mut orders = Collection::new("orders".to_string());
+        for order in [
+            r#"{"customer": "ann", "amount": 10, "items": ["a", "b"], "status": "paid"}"#,
+            r#"{"customer": "bob", "amount": 2.5, "items": ["a"], "status": "paid"}"#,
+            r#"{"customer": "ann", "amount": 5, "items": [], "status": "paid"}"#,
+            r#"{"customer": "eve", "amount": 7, "status": "open"}"#,
+            r#"{"customer": "bob", "amount": 4, "items": ["c"], "status": "paid"}"#,
+        ] {
+            orders.add(Document::from_json(order)).unwrap();
+        }
+        orders
+    }
+
+    fn run(collection: &Collection, pipeline: Value) -> Value {
+        let documents = Pipeline::from_value(&pipeline).unwrap().run(collection);
+        Value::Array(documents.iter().map(|document| serde_json::from_str(&document.to_json()).unwrap()).collect())
+    }
+
+    #[test]
+    fn group_and_sort() {
+        let orders = orders();
+        assert_eq!(run(&orders, json!([
+            {"$match": {"status": "paid"}},
+            {"$group": {
+                "_id": "$customer",
+                "total": {"$sum": "$amount"},
+                "orders": {"$count": {}},
+                "average": {"$avg": "$amount"},
+                "biggest": {"$max": "$amount"},
+                "amounts": {"$push": "$amount"}
+            }},
+            {"$sort": {"total": -1}}
+        ])), json!([
+            {"_id": "ann", "total": 15, "orders": 2, "average": 7.5, "biggest": 10, "amounts": [10, 5]},
+            {"_id": "bob", "total": 6.5, "orders": 2, "average": 3.25, "biggest": 4, "amounts": [2.5, 4]}
+        ]));
+        assert_eq!(run(&orders, json!([
+            {"$group": {"_id": null, "smallest": {"$min": "$amount"}, "n": {"$sum": 1}}}
+        ])), json!([{"_id": null, "smallest": 2.5, "n": 5}]));
+    }
+
+    #[test]
+    fn unwind_project_and_count() {
+        let orders = orders();
+        assert_eq!(run(&orders, json!([
+            {"$unwind": "$items"},
+            {"$project": {"customer": 1, "item": "$items", "ID": 0}},
+            {"$skip": 1},
+            {"$limit": 2}
+        ])), json!([
+            {"customer": "ann", "item": "b"},
+            {"customer": "bob", "item": "a"}
+        ]));
+        assert_eq!(run(&orders, json!([
+            {"$unwind": {"path": "$items", "preserveNullAndEmptyArrays": true}},
+            {"$count": "rows"}
+        ])), json!([{"rows": 6}]));
+        assert_eq!(run(&orders, json!([{"$match": {"amount": {"$gt": 100}}}, {"$count": "rows"}])), json!([]));
+    }
+
+    #[test]
+    fn invalid_pipelines() {
+        for pipeline in [
+            json!({"$match": {}}),
+            json!([{"$unknown": {}}]),
+            json!([{"$limit": -1}]),
+            json!([{"$group": {"total": {"$sum": "$amount"}}}]),
+            json!([{"$group": {"_id": null, "total": {"$median": "$amount"}}}]),
+            json!([{"$project": {"a": 1, "b": 0}}]),
+            json!([{"$sort": {"a": 2}}]),
+            json!([{"$unwind": "items"}]),
+        ] {
+            assert!(Pipeline::from_value(&pipeline).is_err(), "{}", pipeline);
+        }
+    }
+}
@@ -0,0 +1,192 @@
+// The bulk module runs many writes on a collection in one request
+// The operations are a JSON array, or one JSON operation per line (NDJSON):
+//
+//   {"insert": {"name": "John"}}
+//   {"update": {"id": "<uuid>", "update": {"$inc": {"visits": 1}}}}
+//   {"replace": {"id": "<uuid>", "document": {"name": "Jane"}, "upsert": false}}
+//   {"delete": {"id": "<uuid>"}}
+//
+// An update with operators applies them, any other update is merged into the document
+// In ordered mode the first failing operation stops the rest, unordered runs all of them
+// Each operation is written on its own, the operations before a failure are kept
+
+use serde_json::Value;
+use uuid::Uuid;
+use super::collection::{Collection, Document, DocumentJson};
+use super::error::DbError;
+use super::update::Update;
+
+#[derive(Debug)]
+pub(crate) enum Change {
+    Operators(Update),
+    Merge(Document),
+}
+
+#[derive(Debug)]
+pub(crate) enum Operation {
+    Insert(Document),
+    Update { id: Uuid, change: Change },
+    Replace { id: Uuid, document: Document, upsert: bool },
+    Delete { id: Uuid },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Inserted(Uuid),
+    Updated(Uuid),
+    // whether the document was added by the upsert
+    Replaced { id: Uuid, added: bool },
+    Deleted(Uuid),
+    // moved to another collection by a transaction
+    Moved(Uuid),
+}
+
+#[derive(Debug)]
+pub enum Failure {
+    // the operation is not valid
+    Invalid(String),
+    Db(DbError),
+    // not run, an operation before it failed in ordered mode
+    Skipped,
+}
+
+fn document(value: &Value) -> Result<Document, String> {
+    match value {
+        Value::Object(_) => Ok(Document::from_json(&value.to_string())),
+        _ => Err("The document must be a JSON object".to_string()),
+    }
+}
+
+impl Operation {
+    pub(crate) fn parse(value: &Value) -> Result<Operation, String> {
+        let (kind, arguments) = match value.as_object() {
+            Some(object) if object.len() == 1 => object.iter().next().unwrap(),
+            _ => return Err("An operation must be an object with one of insert, update, replace or delete".to_string()),
+        };
+        if kind == "insert" {
+            return Ok(Operation::Insert(document(arguments)?));
+        }
+        let id = match arguments.get("id").and_then(Value::as_str).map(Uuid::parse_str) {
+            Some(Ok(id)) => id,
+            _ => return Err(format!("{} needs the id of the document", kind)),
+        };
+        match kind.as_str() {
+            "update" => {
+                let update = arguments.get("update").ok_or("update needs the update of the document")?;
+                let operators = update.as_object().is_some_and(|object| object.keys().any(|key| key.starts_with('$')));
+                let change = if operators { Change::Operators(Update::from_value(update)?) } else { Change::Merge(document(update)?) };
+                Ok(Operation::Update { id, change })
+            }
+            "replace" => Ok(Operation::Replace {
+                id,
+                document: document(arguments.get("document").unwrap_or(&Value::Null))?,
+                upsert: arguments.get("upsert").and_then(Value::as_bool).unwrap_or(true),
+            }),
+            "delete" => Ok(Operation::Delete { id }),
+            _ => Err(format!("Unknown operation {}", kind)),
+        }
+    }
+
+    fn run(self, collection: &mut Collection) -> Result<Outcome, DbError> {
+        match self {
+            Operation::Insert(document) => collection.add(document).map(Outcome::Inserted),
+            Operation::Update { id, change: Change::Operators(update) } => {
+                collection.patch_document(id, &update).map(|_| Outcome::Updated(id))
+            }
+            Operation::Update { id, change: Change::Merge(document) } => {
+                collection.update_document(id, document).map(|_| Outcome::Updated(id))
+            }
+            Operation::Replace { id, document, upsert } => collection
+                .replace_document(id, document, upsert)
+                .map(|(_, added)| Outcome::Replaced { id, added }),
+            Operation::Delete { id } => collection.rm(id).map(|_| Outcome::Deleted(id)),
+        }
+    }
+}
+
+pub struct Bulk {
+    // an invalid operation fails when its turn comes, the ones before it are still run
+    operations: Vec<Result<Operation, String>>,
+}
+
+impl Bulk {
+    // A JSON array of operations, or one operation per line
+    pub fn parse(body: &str) -> Result<Bulk, String> {
+        let operations = if body.trim_start().starts_with('[') {
+            match serde_json::from_str(body) {
+                Ok(Value::Array(operations)) => operations.iter().map(Operation::parse).collect(),
+                Ok(_) => return Err("The operations must be a JSON array".to_string()),
+                Err(e) => return Err(e.to_string()),
+            }
+        } else {
+            body.lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| serde_json::from_str(line).map_err(|e| e.to_string()).and_then(|value| Operation::parse(&value)))
+                .collect()
+        };
+        Ok(Bulk { operations })
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    // The result of every operation, in order
+    pub fn run(self, collection: &mut Collection, ordered: bool) -> Vec<Result<Outcome, Failure>> {
+        let mut failed = false;
+        self.operations.into_iter().map(|operation| {
+            if failed && ordered {
+                return Err(Failure::Skipped);
+            }
+            let result = match operation {
+                Ok(operation) => operation.run(collection).map_err(Failure::Db),
+                Err(e) => Err(Failure::Invalid(e)),
+            };
+            failed |= result.is_err();
+            result
+        }).collect()
+    }
+}
+
+
+//TEST
+#[cfg(test)]
+mod tests {
+    use crate::doc;
+    use crate::memodb::collection::Collection;
+    use crate::memodb::index::IndexSpec;
+    use super::{Bulk, Failure, Outcome};
+
+    #[test]
+    fn ordered_and_unordered() {
+        let mut collection = Collection::new("users".to_string());
+        collection.create_index(IndexSpec::new("email", &["email"], true)).unwrap();
+        let id = collection.add(doc!{"email" => "a", "visits" => 1}).unwrap();
+        let body = format!(r#"
+            {{"insert": {{"email": "b"}}}}
+            {{"update": {{"id": "{id}", "update": {{"$inc": {{"visits": 1}}}}}}}}
+            {{"insert": {{"email": "a"}}}}
+            not json
+            {{"delete": {{"id": "{id}"}}}}
+        "#);
+        let results = Bulk::parse(&body).unwrap().run(&mut collection, true);
+        assert!(matches!(results[0], Ok(Outcome::Inserted(_))));
+        assert_eq!(results[1].as_ref().unwrap(), &Outcome::Updated(id));
+        assert!(matches!(results[2], Err(Failure::Db(_))));
+        assert!(matches!(results[3], Err(Failure::Skipped)));
+        assert!(matches!(results[4], Err(Failure::Skipped)));
+        assert_eq!(collection.get(id).unwrap().get("visits").unwrap().to_number(), 2);
+
+        let results = Bulk::parse(&body).unwrap().run(&mut collection, false);
+        assert!(matches!(results[0], Err(Failure::Db(_))));
+        assert!(matches!(results[3], Err(Failure::Invalid(_))));
+        assert_eq!(results[4].as_ref().unwrap(), &Outcome::Deleted(id));
+        assert_eq!(collection.count(), 1);
+
+        let body = format!(r#"[{{"replace": {{"id": "{id}", "document": {{"email": "c"}}}}}}, {{"upsert": {{}}}}]"#);
+        let results = Bulk::parse(&body).unwrap().run(&mut collection, false);
+        assert_eq!(results[0].as_ref().unwrap(), &Outcome::Replaced { id, added: true });
+        assert!(matches!(results[1], Err(Failure::Invalid(_))));
+        assert!(Bulk::parse("[{").is_err());
+    }
+}
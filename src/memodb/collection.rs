@@ -5,7 +5,17 @@
 
 use uuid::Uuid;
 use std::collections::HashMap;
+use std::sync::Arc;
+use imbl::{HashMap as SharedMap, OrdMap};
 use super::data_type::DataType;
+use super::path;
+use super::cursor::Cursor;
+use super::error::DbError;
+use super::index::{Index, IndexKey, IndexSpec};
+use super::query::{Condition, Filter};
+use super::sort::Sort;
+use super::update::Update;
+use super::wal::{Record, Wal};
 use serde_json::Value;
 
 const ID: &str = "ID";
@@ -27,44 +37,22 @@ pub trait DocumentJson {
 
 impl DocumentJson for Document {
   fn to_json(&self) -> String {
-    let mut json = serde_json::json!({});
-    for (key, value) in self.iter() {
-      match value {
-        DataType::Id(id) => json[key] = serde_json::json!(id.to_string()),
-        DataType::Text(text) => json[key] = serde_json::json!(text),
-        DataType::Number(number) => json[key] = serde_json::json!(number),
-        DataType::Boolean(boolean) => json[key] = serde_json::json!(boolean),
-        _ => json[key] = serde_json::json!("()")
-      }
-    }
-    json.to_string()
+    let json: serde_json::Map<String, Value> = self.iter().map(|(key, value)| (key.clone(), value.to_value())).collect();
+    Value::Object(json).to_string()
   }
 
   fn from_json(json: &str) -> Self {
       let v: Value = serde_json::from_str(json).unwrap();
       let mut document = Document::new();
       for (key, value) in v.as_object().unwrap() {
-        let value: Value = value.clone();
         if key == ID {
-          let value_is_string = value.is_string();
-          let id = Uuid::parse_str(value.as_str().unwrap());
-          if id.is_ok() && value_is_string {
-            document.insert(key.to_string(), DataType::Id(id.unwrap()));
-          } else {
-            match value {
-              Value::Number(n) => document.insert("id".to_string(), DataType::Number(n.as_i64().unwrap() as i32)),
-              Value::String(s) => document.insert("id".to_string(), DataType::Text(s)),
-              Value::Bool(b) => document.insert("id".to_string(), DataType::Boolean(b)),
-              _ => document.insert("id".to_string(), DataType::Text("".to_string()))
-            };
-          }
-        } else {
-          match value {
-            Value::Number(n) => document.insert(key.to_string(), DataType::Number(n.as_i64().unwrap() as i32)),
-            Value::String(s) => document.insert(key.to_string(), DataType::Text(s)),
-            Value::Bool(b) => document.insert(key.to_string(), DataType::Boolean(b)),
-            _ => document.insert(key.to_string(), DataType::Text("".to_string()))
+          // only a valid UUID can be the ID of a document, other values are kept as "id"
+          match value.as_str().map(Uuid::parse_str) {
+            Some(Ok(id)) => document.insert(key.to_string(), DataType::Id(id)),
+            _ => document.insert("id".to_string(), DataType::from_value(value)),
           };
+        } else {
+          document.insert(key.to_string(), DataType::from_value(value));
         }
       }
   
@@ -93,11 +81,56 @@ macro_rules! doc {
 
 
 
+struct Plan<'a> {
+  index: &'a Index,
+  prefix: Vec<DataType>,
+  range: Vec<&'a Condition>,
+  values: Option<&'a Vec<DataType>>,
+}
+
+// The number of documents matched by a write and the IDs of the ones changed
+#[derive(Debug)]
+pub struct WriteResult {
+  pub matched: usize,
+  pub ids: Vec<Uuid>,
+}
+
+// A document taken out of the collection with its sequence number and revision
+pub(crate) struct Removed {
+  seq: u64,
+  rev: u64,
+  pub(crate) document: Document,
+}
+
+// A document of the collection and its revision, every write of the document increments it
+#[derive(Clone)]
+struct Stored {
+  document: Arc<Document>,
+  rev: u64,
+}
+
+// A page of the documents of a query
+pub struct Page<'a> {
+  pub documents: Vec<&'a Document>,
+  // documents matching the query, in all the pages
+  pub total: usize,
+  // where the next page starts, None in the last page
+  pub next: Option<Cursor>,
+}
+
+// Cloned by every write, the clone shares with the previous version the documents,
+// the maps and the index nodes, and copies only the parts the write changes
+#[derive(Clone)]
 pub struct Collection {
   pub name: String,
-  pub(crate) data: Vec<Document>,
-  id_table: HashMap<Uuid, usize>,
-  //b_tree: BNode
+  // the documents by sequence number, the order in which they were added
+  data: OrdMap<u64, Stored>,
+  // sequence number of each document
+  id_table: SharedMap<Uuid, u64>,
+  pub(crate) next_seq: u64,
+  // every write is logged here before being applied, None for collections not backed by a log
+  pub(crate) wal: Option<Arc<Wal>>,
+  indexes: Vec<Index>,
 }
 
 
@@ -105,22 +138,67 @@ pub struct Collection {
 impl Collection {
   pub fn new(name: String) -> Self {
     Collection {
-      name: name,
-      data: Vec::new(),
-      id_table: HashMap::new(),
-      //b_tree: BNode::new(),
+      name,
+      data: OrdMap::new(),
+      id_table: SharedMap::new(),
+      next_seq: 0,
+      wal: None,
+      indexes: Vec::new(),
     }
   }
 
-  fn update_index(&mut self) {
-    self.id_table.clear();
-    for (index, document) in self.data.iter().enumerate() {
-      let id = document.get(ID).unwrap().to_id();
-      self.id_table.insert(id, index);
+  fn log(&self, record: Record) -> Result<(), DbError> {
+    if let Some(wal) = &self.wal {
+      wal.append(&record)?;
+    }
+    Ok(())
+  }
+
+  // Apply a record read from the write-ahead log
+  pub(crate) fn replay(&mut self, record: Record) {
+    match record {
+      Record::Add { document, .. } => { self.insert(document); }
+      Record::Remove { id, .. } => { self.remove(id); }
+      Record::Update { id, document, .. } => { self.merge(id, document); }
+      Record::Replace { id, document, .. } => { self.set(id, document); }
+      Record::CreateIndex { index, .. } => self.build_index(index),
+      Record::DropIndex { name, .. } => self.indexes.retain(|x| x.spec.name != name),
+      _ => {}
+    }
+  }
+
+  // The documents in insertion order
+  fn documents(&self) -> impl Iterator<Item = &Document> {
+    self.data.values().map(|stored| &*stored.document)
+  }
+
+  // The documents in insertion order with their sequence numbers and revisions
+  pub(crate) fn entries(&self) -> impl Iterator<Item = (u64, u64, &Document)> {
+    self.data.iter().map(|(seq, stored)| (*seq, stored.rev, &*stored.document))
+  }
+
+  fn by_id(&self, id: &Uuid) -> Option<&Document> {
+    self.data.get(self.id_table.get(id)?).map(|stored| &*stored.document)
+  }
+
+  // Insert a document that already has a unique ID
+  pub(crate) fn insert(&mut self, document: Document) -> Uuid {
+    self.insert_at(document, self.next_seq, 1)
+  }
+
+  // Insert a document with the sequence number and revision it had, in a snapshot or before being removed
+  pub(crate) fn insert_at(&mut self, document: Document, seq: u64, rev: u64) -> Uuid {
+    let id = document.get(ID).unwrap().to_id();
+    for index in self.indexes.iter_mut() {
+      index.insert(id, &document);
     }
+    self.data.insert(seq, Stored { document: Arc::new(document), rev });
+    self.id_table.insert(id, seq);
+    self.next_seq = self.next_seq.max(seq + 1);
+    id
   }
 
-  pub fn add(&mut self, document: Document) -> Uuid {
+  pub fn add(&mut self, document: Document) -> Result<Uuid, DbError> {
     let mut document = document;
     if !document.contains_key(ID) {
       let id = Uuid::new_v4();
@@ -134,17 +212,50 @@ impl Collection {
         document.insert(ID.to_string(), DataType::Id(id));
       }
     }
-    let id = document.get(ID).unwrap().to_id();
-    self.data.push(document);
-    self.id_table.insert(id, self.data.len() - 1);
-    id
+    self.check_unique(document.get(ID).unwrap().to_id(), &document)?;
+    self.log(Record::Add { collection: self.name.clone(), document: document.clone() })?;
+    Ok(self.insert(document))
+  }
+
+  // Remove a document, returning it with its place in the collection so it can be put back
+  pub(crate) fn take(&mut self, id: Uuid) -> Option<Removed> {
+    let seq = *self.id_table.get(&id)?;
+    let rev = self.data.get(&seq)?.rev;
+    Some(Removed { seq, rev, document: self.remove(id)? })
   }
 
-  pub fn rm(&mut self, id: Uuid) {
-    //self.data.remove(index);
-    let index = self.get_index(id);
-    self.data.swap_remove(index);
-    self.update_index();
+  // Undo the insert of a document, its sequence number is given again when it was the last one,
+  // as the replay of the log would do, so the ETags and cursors of the next documents don't
+  // change after a restart
+  pub(crate) fn take_back(&mut self, id: Uuid) {
+    if let Some(removed) = self.take(id) {
+      if removed.seq + 1 == self.next_seq {
+        self.next_seq = removed.seq;
+      }
+    }
+  }
+
+  // Put back a document removed by `take`
+  pub(crate) fn put_back(&mut self, removed: Removed) {
+    self.insert_at(removed.document, removed.seq, removed.rev);
+  }
+
+  pub(crate) fn remove(&mut self, id: Uuid) -> Option<Document> {
+    let seq = self.id_table.remove(&id)?;
+    let stored = self.data.remove(&seq)?;
+    for index in self.indexes.iter_mut() {
+      index.remove(id, &stored.document);
+    }
+    Some(Arc::unwrap_or_clone(stored.document))
+  }
+
+  pub fn rm(&mut self, id: Uuid) -> Result<(), DbError> {
+    if !self.id_table.contains_key(&id) {
+      return Err(DbError::DocumentNotFound);
+    }
+    self.log(Record::Remove { collection: self.name.clone(), id })?;
+    self.remove(id);
+    Ok(())
   }
 
   pub fn count(&self) -> usize {
@@ -152,74 +263,437 @@ impl Collection {
   }
 
   fn _get(&self, index: usize) -> Option<&Document> {
-    self.data.get(index)
+    self.documents().nth(index)
   }
 
-  fn get_index(&self, id: Uuid) -> usize {
-    let id = DataType::Id(id);
-    self.data.iter().position(|x| x.get(ID).unwrap() == &id).unwrap()
+  fn seq(&self, document: &Document) -> u64 {
+    self.id_table[&document.get(ID).unwrap().to_id()]
   }
 
-  pub fn get_all(&self,limit: usize, offset: usize) -> Vec<Document> {
-    if limit == 0 {
-      return self.data.clone()
+  // A page of `documents`, the result of query_sorted when there is a sort or of any other query
+  // The page starts after the cursor, skips `offset` documents and has at most `limit` of them,
+  // all of them when limit is 0; without a sort the documents are in insertion order
+  pub fn page<'a>(&'a self, documents: Vec<&'a Document>, sort: Option<&Sort>, after: Option<&Cursor>, offset: usize, limit: usize) -> Page<'a> {
+    let mut documents = documents;
+    if sort.is_none() {
+      documents.sort_by_cached_key(|document| self.seq(document));
     }
-    // let limit = limit+offset;
-    // if limit > self.data.len() {
-    //   return self.data[offset..].to_vec()
-    // }
-    self.data[offset..limit].to_vec()
-   }
+    let total = documents.len();
+    // the documents are ordered by their sort values, and the ties by their sequence numbers
+    let position = |document: &Document| -> (IndexKey, u64) {
+      let key = sort.map(|sort| sort.key(document)).unwrap_or(IndexKey(Vec::new()));
+      (key, self.seq(document))
+    };
+    let start = match after {
+      Some(after) => {
+        let after = after.position(sort);
+        documents.partition_point(|document| position(document) <= after)
+      }
+      None => 0,
+    };
+    let start = start.saturating_add(offset).min(documents.len());
+    let end = if limit == 0 { documents.len() } else { start.saturating_add(limit).min(documents.len()) };
+    let next = match documents[start..end].last() {
+      Some(last) if end < documents.len() => {
+        let (key, seq) = position(last);
+        Some(Cursor::new(sort, key, seq))
+      }
+      _ => None,
+    };
+    documents.truncate(end);
+    documents.drain(..start);
+    Page { documents, total, next }
+  }
 
   fn _find_by_key(&self, key: &str) -> Vec<&Document> {
-    self.data.iter().filter(|&x| x.contains_key(key)).collect()
+    self.documents().filter(|x| x.contains_key(key)).collect()
   }
 
   fn _find_by_value(&self, key: &str, value: &DataType) -> Vec<&Document> {
-    self.data.iter().filter(|&x| x.contains_key(key) && x.get(key).unwrap() == value).collect()
+    self.documents().filter(|x| x.contains_key(key) && x.get(key).unwrap() == value).collect()
+  }
+
+  // the criteria of find are equalities, and the values of the ID are given as text
+  pub fn criteria(args: HashMap<String, DataType>) -> Vec<Filter> {
+    args.into_iter().map(|(key, value)| {
+      let value = match value.to_string().parse::<Uuid>() {
+        Ok(id) if key == ID => DataType::Id(id),
+        _ => value,
+      };
+      Filter::Field(key, Condition::Eq(value))
+    }).collect()
   }
 
+  // Return the documents matching all the criteria, each document at most once
   pub fn find(&self, args: HashMap<String, DataType>) -> Vec<&Document> {
-    let mut result = Vec::new();
-    for (key, value) in args.iter() {
-      let is_id = value.to_string().parse::<Uuid>();
-      if key == ID && is_id.is_ok() {
-        let id = value.to_id();
-        let index = self.id_table.get(&id);
-        match index {
-          Some(index) => result.push(self._get(*index).unwrap()),
-          None => continue
+    let id = args.get(ID).and_then(|value| value.to_string().parse::<Uuid>().ok());
+    let filter = Filter::And(Collection::criteria(args));
+    match id {
+      // only the document with that ID can match
+      Some(id) => self.by_id(&id)
+        .filter(|document| filter.matches(document))
+        .into_iter()
+        .collect(),
+      None => self.query(&filter),
+    }
+  }
+
+  // Return the documents matching any of the criteria, each document at most once
+  pub fn find_any(&self, args: HashMap<String, DataType>) -> Vec<&Document> {
+    self.query(&Filter::Or(Collection::criteria(args)))
+  }
+
+  // The conditions that all the matching documents must meet, nested $and included
+  fn conjuncts<'a>(filter: &'a Filter, out: &mut Vec<(&'a String, &'a Condition)>) {
+    match filter {
+      Filter::Field(field, condition) => out.push((field, condition)),
+      Filter::And(filters) => filters.iter().for_each(|filter| Collection::conjuncts(filter, out)),
+      _ => {}
+    }
+  }
+
+  // How an index can be scanned for a filter: the values of its first fields compared with $eq,
+  // and the $gt, $gte, $lt and $lte conditions on the next one
+  fn plans<'a>(&'a self, filter: &'a Filter) -> Vec<Plan<'a>> {
+    let mut conditions = Vec::new();
+    Collection::conjuncts(filter, &mut conditions);
+    let on = |field: &String| -> Vec<&Condition> {
+      conditions.iter().filter(|(name, _)| *name == field).map(|(_, condition)| *condition).collect()
+    };
+    self.indexes.iter().map(|index| {
+      let mut prefix = Vec::new();
+      for field in index.spec.fields.iter() {
+        // the keys of arrays are their elements, an index can't find a whole array
+        let equal = on(&field.name).into_iter().find_map(|condition| match condition {
+          Condition::Eq(value) if !matches!(value, DataType::Array(_)) => Some(value),
+          _ => None,
+        });
+        match equal {
+          Some(value) => prefix.push(value.clone()),
+          None => break,
         }
-      } else {
-        result.append(&mut self._find_by_value(key, value));
+      }
+      let range: Vec<&Condition> = match index.spec.fields.get(prefix.len()) {
+        Some(field) => on(&field.name).into_iter()
+          .filter(|condition| matches!(condition, Condition::Gt(_) | Condition::Gte(_) | Condition::Lt(_) | Condition::Lte(_)))
+          .collect(),
+        None => Vec::new(),
+      };
+      // a list of values of the first field
+      let values = on(&index.spec.fields[0].name).into_iter().find_map(|condition| match condition {
+        Condition::In(values) if !values.iter().any(|value| matches!(value, DataType::Array(_))) => Some(values),
+        _ => None,
+      });
+      Plan { index, prefix, range, values }
+    }).collect()
+  }
+
+  // IDs of the documents that can match the filter, in the order of the index used,
+  // when an index can answer some of its conditions
+  // The best index is the one with the longest prefix of fields compared with $eq,
+  // followed by a field compared with $gt, $gte, $lt or $lte
+  fn candidates(&self, filter: &Filter) -> Option<Vec<Uuid>> {
+    let plans = self.plans(filter);
+    let best = plans.iter()
+      .filter(|plan| !plan.prefix.is_empty() || !plan.range.is_empty())
+      .rev()
+      .max_by_key(|plan| (plan.prefix.len(), !plan.range.is_empty()));
+    if let Some(plan) = best {
+      return Some(plan.index.scan(&plan.prefix, &plan.range));
+    }
+    plans.iter().find_map(|plan| Some(plan.index.scan_in(plan.values?)))
+  }
+
+  // Return the documents matching the filter, in the order of the index used to find them
+  // or in the insertion order when there is no index for the filter
+  pub fn query(&self, filter: &Filter) -> Vec<&Document> {
+    match self.candidates(filter) {
+      Some(ids) => ids.iter()
+        .filter_map(|id| self.by_id(id))
+        .filter(|document| filter.matches(document))
+        .collect(),
+      None => self.documents().filter(|document| filter.matches(document)).collect(),
+    }
+  }
+
+  // Return the documents matching the filter ordered by `sort`
+  // An index gives the order when its last fields are the sort fields, and the fields before
+  // them are compared with $eq in the filter, otherwise the documents are sorted in memory
+  pub fn query_sorted(&self, filter: &Filter, sort: &Sort) -> Vec<&Document> {
+    let plan = self.plans(filter).into_iter().find(|plan| {
+      let fields = &plan.index.spec.fields;
+      !plan.index.multikey
+        && fields.len() >= sort.fields.len()
+        && fields.len() - sort.fields.len() <= plan.prefix.len()
+        && fields.ends_with(&sort.fields)
+    });
+    match plan {
+      // the documents with the same values keep their insertion order
+      Some(plan) => plan.index.scan_groups(&plan.prefix, &plan.range).into_iter().flat_map(|ids| {
+        let mut seqs: Vec<u64> = ids.iter().filter_map(|id| self.id_table.get(id).cloned()).collect();
+        seqs.sort_unstable();
+        seqs
+      })
+        .filter_map(|seq| self.data.get(&seq))
+        .map(|stored| &*stored.document)
+        .filter(|document| filter.matches(document))
+        .collect(),
+      None => {
+        let mut documents = self.query(filter);
+        // an index used by the query gives its own order, the ties keep the insertion order
+        documents.sort_by_cached_key(|document| self.seq(document));
+        sort.apply(&mut documents);
+        documents
+      }
+    }
+  }
+
+  // Every value of `field` in the documents matching the filter once, and every element
+  // of the arrays, in the order of the indexes
+  // With no filter an index starting with the field already has the values
+  pub fn distinct(&self, field: &str, filter: &Filter) -> Vec<DataType> {
+    let unfiltered = matches!(filter, Filter::And(filters) if filters.is_empty());
+    let index = self.indexes.iter().find(|index| index.spec.fields[0].name == field);
+    if let (true, Some(index)) = (unfiltered, index) {
+      let mut values = index.first_values();
+      if index.spec.fields[0].descending {
+        values.reverse();
+      }
+      return values;
+    }
+    let mut values: Vec<DataType> = Vec::new();
+    for document in self.query(filter) {
+      for value in path::values(document, field) {
+        match value {
+          DataType::Array(items) => values.extend(items.iter().cloned()),
+          value => values.push(value.clone()),
+        }
+      }
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    values.dedup_by(|a, b| a.total_cmp(b).is_eq());
+    values
+  }
+
+  // Build an index over the documents, failing on the first duplicate of a unique index
+  fn new_index(&self, spec: IndexSpec) -> Result<Index, DbError> {
+    let mut index = Index::new(spec);
+    for document in self.documents() {
+      let id = document.get(ID).unwrap().to_id();
+      if let Some(other) = index.conflict(id, document) {
+        return Err(DbError::UniqueViolation { index: index.spec.name.clone(), id: other });
+      }
+      index.insert(id, document);
+    }
+    Ok(index)
+  }
+
+  // Add an index already checked when it was created
+  pub(crate) fn build_index(&mut self, spec: IndexSpec) {
+    let mut index = Index::new(spec);
+    for document in self.documents() {
+      index.insert(document.get(ID).unwrap().to_id(), document);
+    }
+    self.indexes.push(index);
+  }
+
+  // Create a secondary index on one or more fields, used by the queries with conditions on its first fields
+  pub fn create_index(&mut self, spec: IndexSpec) -> Result<(), DbError> {
+    if self.indexes.iter().any(|x| x.spec.name == spec.name) {
+      return Err(DbError::IndexExists);
+    }
+    let index = self.new_index(spec)?;
+    self.log(Record::CreateIndex { collection: self.name.clone(), index: index.spec.clone() })?;
+    self.indexes.push(index);
+    Ok(())
+  }
+
+  // Check the unique indexes before writing `document` as the document `id`
+  fn check_unique(&self, id: Uuid, document: &Document) -> Result<(), DbError> {
+    for index in self.indexes.iter() {
+      if let Some(other) = index.conflict(id, document) {
+        return Err(DbError::UniqueViolation { index: index.spec.name.clone(), id: other });
       }
     }
-    result
+    Ok(())
+  }
+
+  pub fn drop_index(&mut self, name: &str) -> Result<(), DbError> {
+    let position = self.indexes.iter().position(|x| x.spec.name == name).ok_or(DbError::IndexNotFound)?;
+    self.log(Record::DropIndex { collection: self.name.clone(), name: name.to_string() })?;
+    self.indexes.remove(position);
+    Ok(())
   }
 
-  fn slow_get(&mut self, id: Uuid) -> Option<&mut Document> {
+  pub fn get_indexes(&self) -> Vec<&IndexSpec> {
+    self.indexes.iter().map(|index| &index.spec).collect()
+  }
+
+  fn slow_get(&self, id: Uuid) -> Option<&Document> {
     let id = DataType::Id(id);
-    self.data.iter_mut().find(|x| x.get(ID).unwrap() == &id)
+    self.documents().find(|x| x.get(ID).unwrap() == &id)
 
 
   }
 
-  pub fn get(&mut self, id: Uuid) -> Option<&mut Document> {
-    let index = self.id_table.get(&id);
-    match index {
-      Some(index) => self.data.get_mut(*index),
+  pub fn get(&self, id: Uuid) -> Option<&Document> {
+    match self.by_id(&id) {
+      Some(document) => Some(document),
       None => self.slow_get(id)
     }
   }
 
 
-  pub fn update_document(&mut self,id: Uuid, new_document: Document) -> Option<&Document> {
-    let document = self.get(id).unwrap();
-    for (key, val) in new_document.iter() {
-      document.remove(key);
-      document.insert(key.to_string(), val.clone());
+  // Replace the document `id` keeping the indexes up to date
+  pub(crate) fn set(&mut self, id: Uuid, new_document: Document) -> Option<&Document> {
+    let seq = *self.id_table.get(&id)?;
+    let stored = self.data.get_mut(&seq)?;
+    stored.rev += 1;
+    for index in self.indexes.iter_mut() {
+      index.remove(id, &stored.document);
+      index.insert(id, &new_document);
+    }
+    stored.document = Arc::new(new_document);
+    Some(&*stored.document)
+  }
+
+  // The document `id` and its revision, saved before a write to revert it
+  pub(crate) fn saved(&self, id: Uuid) -> Option<(Document, u64)> {
+    let stored = self.data.get(self.id_table.get(&id)?)?;
+    Some((Document::clone(&stored.document), stored.rev))
+  }
+
+  // Put back the document `id` and the revision saved before a write
+  pub(crate) fn revert(&mut self, id: Uuid, (document, rev): (Document, u64)) {
+    if self.set(id, document).is_some() {
+      let seq = self.id_table[&id];
+      self.data.get_mut(&seq).unwrap().rev = rev;
+    }
+  }
+
+  // The version of the document `id`, changed by every write of the document
+  // The sequence number tells apart a document removed and added again with the same ID
+  pub fn etag(&self, id: Uuid) -> Option<String> {
+    let seq = *self.id_table.get(&id)?;
+    Some(format!("\"{}-{}\"", seq, self.data.get(&seq)?.rev))
+  }
+
+  fn merged(&self, id: Uuid, new_document: Document) -> Option<Document> {
+    let mut document = self.by_id(&id)?.clone();
+    for (key, val) in new_document.into_iter() {
+      // the ID is what indexes the document, it can't be changed
+      if key == ID { continue; }
+      document.insert(key, val);
+    }
+    Some(document)
+  }
+
+  fn merge(&mut self, id: Uuid, new_document: Document) -> Option<&Document> {
+    let document = self.merged(id, new_document)?;
+    self.set(id, document)
+  }
+
+  // Apply the update to every document matching the filter, all of them or none
+  // With `dry_run` nothing is written and the result tells which documents would change
+  pub fn update_many(&mut self, filter: &Filter, update: &Update, dry_run: bool) -> Result<WriteResult, DbError> {
+    let mut matched = 0;
+    let mut changes = Vec::new();
+    for document in self.query(filter) {
+      matched += 1;
+      let mut updated = document.clone();
+      let id = document.get(ID).unwrap().to_id();
+      update.apply(&mut updated).map_err(|e| DbError::InvalidUpdate(format!("{} in document {}", e, id)))?;
+      if updated != *document {
+        changes.push((id, updated));
+      }
+    }
+    let ids = changes.iter().map(|(id, _)| *id).collect();
+    if !dry_run {
+      self.write_batch(changes)?;
+    }
+    Ok(WriteResult { matched, ids })
+  }
+
+  // Remove every document matching the filter, all of them or none
+  pub fn delete_many(&mut self, filter: &Filter, dry_run: bool) -> Result<WriteResult, DbError> {
+    let ids: Vec<Uuid> = self.query(filter).iter().map(|document| document.get(ID).unwrap().to_id()).collect();
+    if !dry_run && !ids.is_empty() {
+      let records = ids.iter().map(|id| Record::Remove { collection: self.name.clone(), id: *id }).collect();
+      self.log(Record::Batch { records })?;
+      for id in ids.iter() {
+        self.remove(*id);
+      }
+    }
+    Ok(WriteResult { matched: ids.len(), ids })
+  }
+
+  // Replace the documents of `changes`, all of them or none
+  // Each document is checked after the previous ones are written, so two documents
+  // of the batch can't take the same values of a unique index
+  fn write_batch(&mut self, changes: Vec<(Uuid, Document)>) -> Result<(), DbError> {
+    if changes.is_empty() {
+      return Ok(());
+    }
+    let mut undo = Vec::new();
+    for (id, document) in changes.iter() {
+      if let Err(e) = self.check_unique(*id, document) {
+        self.restore(undo);
+        return Err(e);
+      }
+      let saved = self.saved(*id).unwrap();
+      self.set(*id, document.clone());
+      undo.push((*id, saved));
+    }
+    let records = changes.into_iter()
+      .map(|(id, document)| Record::Replace { collection: self.name.clone(), id, document })
+      .collect();
+    if let Err(e) = self.log(Record::Batch { records }) {
+      self.restore(undo);
+      return Err(e);
     }
-    return Some(document);
+    Ok(())
+  }
+
+  // Put back the documents replaced by a batch, the last one first
+  fn restore(&mut self, undo: Vec<(Uuid, (Document, u64))>) {
+    for (id, saved) in undo.into_iter().rev() {
+      self.revert(id, saved);
+    }
+  }
+
+  // Replace the whole document `id` keeping its ID, or add it with that ID when there is none
+  // and `upsert` is true, returns the document and whether it was added
+  pub fn replace_document(&mut self, id: Uuid, document: Document, upsert: bool) -> Result<(&Document, bool), DbError> {
+    let mut document = document;
+    document.insert(ID.to_string(), DataType::Id(id));
+    let exists = self.id_table.contains_key(&id);
+    if !exists && !upsert {
+      return Err(DbError::DocumentNotFound);
+    }
+    self.check_unique(id, &document)?;
+    if exists {
+      self.log(Record::Replace { collection: self.name.clone(), id, document: document.clone() })?;
+      return Ok((self.set(id, document).unwrap(), false));
+    }
+    self.log(Record::Add { collection: self.name.clone(), document: document.clone() })?;
+    self.insert(document);
+    Ok((self.by_id(&id).unwrap(), true))
+  }
+
+  // Apply the update operators to the document `id`, all of them or none
+  pub fn patch_document(&mut self, id: Uuid, update: &Update) -> Result<&Document, DbError> {
+    let mut document = self.get(id).ok_or(DbError::DocumentNotFound)?.clone();
+    update.apply(&mut document).map_err(DbError::InvalidUpdate)?;
+    self.check_unique(id, &document)?;
+    self.log(Record::Replace { collection: self.name.clone(), id, document: document.clone() })?;
+    Ok(self.set(id, document).unwrap())
+  }
+
+  pub fn update_document(&mut self,id: Uuid, new_document: Document) -> Result<&Document, DbError> {
+    let document = self.merged(id, new_document.clone()).ok_or(DbError::DocumentNotFound)?;
+    self.check_unique(id, &document)?;
+    self.log(Record::Update { collection: self.name.clone(), id, document: new_document })?;
+    Ok(self.set(id, document).unwrap())
   }
 
 }
@@ -228,7 +702,17 @@ impl Collection {
 //TEST
 #[cfg(test)]
 mod tests {
-  use crate::memodb::collection::Collection;
+  use std::collections::HashMap;
+  use proptest::prelude::*;
+  use serde_json::Value;
+  use uuid::Uuid;
+  use crate::memodb::collection::{Collection, Document, DocumentJson};
+  use crate::memodb::data_type::DataType;
+  use crate::memodb::error::DbError;
+  use crate::memodb::index::IndexSpec;
+  use crate::memodb::query::Filter;
+  use crate::memodb::sort::Sort;
+  use crate::memodb::update::Update;
   use crate::doc;
 
   #[test]
@@ -239,7 +723,369 @@ mod tests {
       "age" => 25, 
       "isMarried" => false, 
       "birthDate" => "1995-01-01"
-    ));
+    )).unwrap();
     assert!(collection._get(0).is_some());
   }
+
+  #[test]
+  fn test_query() {
+    let mut collection = Collection::new("users".to_string());
+    collection.add(doc!("name" => "John", "age" => 30)).unwrap();
+    collection.add(doc!("name" => "Jane", "age" => 25)).unwrap();
+    collection.add(doc!("name" => "Doe", "age" => 40)).unwrap();
+    let filter = Filter::from_json(r#"{"age": {"$gte": 30}}"#).unwrap();
+    let mut names: Vec<String> = collection.query(&filter).iter().map(|x| x.get("name").unwrap().to_string()).collect();
+    names.sort();
+    assert_eq!(names, vec!["Doe", "John"]);
+  }
+
+  fn users() -> Collection {
+    let mut collection = Collection::new("users".to_string());
+    collection.add(doc!("name" => "John", "age" => 30)).unwrap();
+    collection.add(doc!("name" => "John", "age" => 25)).unwrap();
+    collection.add(doc!("name" => "Jane", "age" => 30)).unwrap();
+    collection
+  }
+
+  fn names(documents: Vec<&Document>) -> Vec<String> {
+    let mut names: Vec<String> = documents.iter().map(|x| format!("{} {}", x.get("name").unwrap().to_string(), x.get("age").unwrap().to_string())).collect();
+    names.sort();
+    names
+  }
+
+  fn names_only(documents: Vec<&Document>) -> Vec<String> {
+    documents.iter().map(|x| x.get("name").unwrap().to_string()).collect()
+  }
+
+  #[test]
+  fn find_matches_all_criteria() {
+    let collection = users();
+    let args = HashMap::from([("name".to_string(), DataType::from("John")), ("age".to_string(), DataType::from(30))]);
+    assert_eq!(names(collection.find(args)), vec!["John 30"]);
+    let args = HashMap::from([("name".to_string(), DataType::from("John"))]);
+    assert_eq!(names(collection.find(args)), vec!["John 25", "John 30"]);
+  }
+
+  #[test]
+  fn find_any_returns_each_document_once() {
+    let collection = users();
+    let args = HashMap::from([("name".to_string(), DataType::from("John")), ("age".to_string(), DataType::from(30))]);
+    assert_eq!(names(collection.find_any(args)), vec!["Jane 30", "John 25", "John 30"]);
+  }
+
+  #[test]
+  fn query_with_index() {
+    let mut collection = users();
+    collection.create_index(IndexSpec::new("age", &["age"], false)).unwrap();
+    assert!(collection.create_index(IndexSpec::new("age", &["name"], false)).is_err());
+    let filter = Filter::from_json(r#"{"age": {"$lt": 30}, "name": {"$exists": true}}"#).unwrap();
+    assert_eq!(collection.candidates(&filter).unwrap().len(), 1);
+    assert_eq!(names(collection.query(&filter)), vec!["John 25"]);
+
+    // the index follows the writes
+    let jane = collection.find(HashMap::from([("name".to_string(), DataType::from("Jane"))]))[0].get("ID").unwrap().to_id();
+    collection.update_document(jane, doc!("age" => 20)).unwrap();
+    let id = collection.add(doc!("name" => "Doe", "age" => 10)).unwrap();
+    collection.rm(id).unwrap();
+    assert_eq!(names(collection.query(&filter)), vec!["Jane 20", "John 25"]);
+
+    collection.drop_index("age").unwrap();
+    assert!(collection.candidates(&filter).is_none());
+    assert_eq!(names(collection.query(&filter)), vec!["Jane 20", "John 25"]);
+  }
+
+  #[test]
+  fn query_with_compound_index() {
+    let mut collection = Collection::new("events".to_string());
+    for (tenant, created) in [("a", 1), ("b", 5), ("a", 3), ("a", 7), ("a", 5)] {
+      collection.add(doc!("tenant" => tenant, "created" => created)).unwrap();
+    }
+    collection.create_index(IndexSpec::new("tenant", &["tenant"], false)).unwrap();
+    collection.create_index(IndexSpec::new("tenant_created", &["tenant", "-created"], false)).unwrap();
+    let created = |documents: Vec<&Document>| -> Vec<i32> { documents.iter().map(|x| x.get("created").unwrap().to_number()).collect() };
+
+    // the longest prefix wins, the documents come in the order of the index
+    let filter = Filter::from_json(r#"{"tenant": "a", "created": {"$gte": 3, "$lt": 7}}"#).unwrap();
+    assert_eq!(collection.candidates(&filter).unwrap().len(), 2);
+    assert_eq!(created(collection.query(&filter)), vec![5, 3]);
+    let filter = Filter::from_json(r#"{"tenant": "a"}"#).unwrap();
+    assert_eq!(created(collection.query(&filter)), vec![1, 3, 7, 5]);
+    collection.drop_index("tenant").unwrap();
+    assert_eq!(created(collection.query(&filter)), vec![7, 5, 3, 1]);
+  }
+
+  #[test]
+  fn distinct_values() {
+    let mut collection = Collection::new("posts".to_string());
+    collection.add(Document::from_json(r#"{"tags": ["rust", "db"], "stars": 2}"#)).unwrap();
+    collection.add(Document::from_json(r#"{"tags": "go", "stars": 2.0}"#)).unwrap();
+    collection.add(Document::from_json(r#"{"tags": ["db"], "stars": null}"#)).unwrap();
+    collection.add(Document::from_json(r#"{"stars": 5}"#)).unwrap();
+    let all = Filter::And(Vec::new());
+    let texts = |values: Vec<DataType>| -> Vec<String> { values.iter().map(|x| x.to_value().to_string()).collect() };
+    assert_eq!(texts(collection.distinct("tags", &all)), vec!["\"db\"", "\"go\"", "\"rust\""]);
+    assert_eq!(texts(collection.distinct("stars", &all)), vec!["null", "2", "5"]);
+    let filter = Filter::from_json(r#"{"stars": 2}"#).unwrap();
+    assert_eq!(texts(collection.distinct("tags", &filter)), vec!["\"db\"", "\"go\"", "\"rust\""]);
+    // the same values from an index
+    collection.create_index(IndexSpec::new("tags", &["-tags"], false)).unwrap();
+    assert_eq!(texts(collection.distinct("tags", &all)), vec!["\"db\"", "\"go\"", "\"rust\""]);
+    collection.create_index(IndexSpec::new("stars", &["stars"], false)).unwrap();
+    assert_eq!(texts(collection.distinct("stars", &all)), vec!["null", "2", "5"]);
+  }
+
+  #[test]
+  fn query_nested_with_index() {
+    let mut collection = Collection::new("users".to_string());
+    collection.add(Document::from_json(r#"{"name": "John", "tags": ["rust", "db"], "address": {"city": "Madrid"}}"#)).unwrap();
+    collection.add(Document::from_json(r#"{"name": "Jane", "tags": ["go"], "address": {"city": "Paris"}}"#)).unwrap();
+    collection.create_index(IndexSpec::new("tags", &["tags"], false)).unwrap();
+    collection.create_index(IndexSpec::new("city", &["address.city"], false)).unwrap();
+    let found = |filter: &str| -> Vec<String> {
+      let filter = Filter::from_json(filter).unwrap();
+      collection.query(&filter).iter().map(|x| x.get("name").unwrap().to_string()).collect()
+    };
+    assert_eq!(found(r#"{"tags": "rust"}"#), vec!["John"]);
+    assert_eq!(found(r#"{"tags": ["go"]}"#), vec!["Jane"]);
+    assert_eq!(found(r#"{"tags": {"$in": ["db", "go"]}}"#).len(), 2);
+    assert_eq!(found(r#"{"address.city": "Paris"}"#), vec!["Jane"]);
+    let args = HashMap::from([("address.city".to_string(), DataType::from("Madrid"))]);
+    assert_eq!(names_only(collection.find(args)), vec!["John"]);
+  }
+
+  #[test]
+  fn sorted_query() {
+    let mut collection = Collection::new("events".to_string());
+    let mut ids = Vec::new();
+    for (n, (tenant, created)) in [("a", 3), ("b", 1), ("a", 1), ("a", 2), ("a", 3), ("b", 2), ("a", 1)].into_iter().enumerate() {
+      ids.push(collection.add(doc!("n" => n as i32, "tenant" => tenant, "created" => created)).unwrap());
+    }
+    collection.add(doc!("n" => 7, "tenant" => "a")).unwrap();
+    collection.rm(ids[0]).unwrap();
+    let order = |collection: &Collection, filter: &str, sort: &str| -> Vec<i32> {
+      let filter = Filter::from_json(filter).unwrap();
+      collection.query_sorted(&filter, &Sort::parse(sort).unwrap()).iter().map(|x| x.get("n").unwrap().to_number()).collect()
+    };
+    let cases = [
+      (r#"{"tenant": "a"}"#, "-created", vec![4, 3, 2, 6, 7]),
+      (r#"{"tenant": "a", "created": {"$lt": 3}}"#, "created", vec![2, 6, 3]),
+      ("{}", "tenant,created", vec![7, 2, 6, 3, 4, 1, 5]),
+    ];
+    for (filter, sort, expected) in cases.iter() {
+      assert_eq!(&order(&collection, filter, sort), expected);
+    }
+    // the same order from the index, ties keep the insertion order
+    collection.create_index(IndexSpec::new("tenant_created", &["tenant", "-created"], false)).unwrap();
+    collection.create_index(IndexSpec::new("tenant_created_asc", &["tenant", "created"], false)).unwrap();
+    let id = collection.find(HashMap::from([("n".to_string(), DataType::from(2))]))[0].get("ID").unwrap().to_id();
+    collection.update_document(id, doc!("tenant" => "a")).unwrap();
+    for (filter, sort, expected) in cases.iter() {
+      assert_eq!(&order(&collection, filter, sort), expected);
+    }
+  }
+
+  #[test]
+  fn pages_with_cursors() {
+    let mut collection = Collection::new("events".to_string());
+    let mut ids = Vec::new();
+    for n in 0..6 {
+      ids.push(collection.add(doc!("n" => n, "group" => n % 2)).unwrap());
+    }
+    let numbers = |documents: &Vec<&Document>| -> Vec<i32> { documents.iter().map(|x| x.get("n").unwrap().to_number()).collect() };
+    let all = Filter::And(Vec::new());
+
+    let page = collection.page(collection.query(&all), None, None, 0, 2);
+    assert_eq!((numbers(&page.documents), page.total), (vec![0, 1], 6));
+    let cursor = page.next.unwrap();
+    // the next page doesn't move when documents are removed or added
+    collection.rm(ids[0]).unwrap();
+    collection.rm(ids[2]).unwrap();
+    collection.add(doc!("n" => 6, "group" => 0)).unwrap();
+    let page = collection.page(collection.query(&all), None, Some(&cursor), 0, 2);
+    assert_eq!((numbers(&page.documents), page.total), (vec![3, 4], 5));
+    let page = collection.page(collection.query(&all), None, page.next.as_ref(), 0, 2);
+    assert_eq!(numbers(&page.documents), vec![5, 6]);
+    assert!(page.next.is_none());
+
+    // sorted by group descending, the ties in insertion order
+    let sort = Sort::parse("-group").unwrap();
+    let page = collection.page(collection.query_sorted(&all, &sort), Some(&sort), None, 1, 2);
+    assert_eq!(numbers(&page.documents), vec![3, 5]);
+    let cursor = page.next.unwrap();
+    collection.add(doc!("n" => 7, "group" => 1)).unwrap();
+    let page = collection.page(collection.query_sorted(&all, &sort), Some(&sort), Some(&cursor), 0, 0);
+    assert_eq!(numbers(&page.documents), vec![7, 4, 6]);
+    assert_eq!(page.total, 6);
+  }
+
+  #[test]
+  fn unique_index() {
+    let mut collection = users();
+    let spec = |fields: &[&str]| IndexSpec::new(&fields.join("_"), fields, true);
+    // there are two Johns already
+    let john = collection.find(HashMap::from([("name".to_string(), DataType::from("John"))]))[0].get("ID").unwrap().to_id();
+    match collection.create_index(spec(&["name"])) {
+      Err(DbError::UniqueViolation { index, .. }) => assert_eq!(index, "name"),
+      _ => panic!("expected a unique violation"),
+    }
+    collection.create_index(spec(&["name", "age"])).unwrap();
+
+    match collection.add(doc!("name" => "John", "age" => 30)) {
+      Err(DbError::UniqueViolation { index, id }) => {
+        assert_eq!(index, "name_age");
+        assert_eq!(collection.get(id).unwrap().get("age").unwrap().to_number(), 30);
+      }
+      _ => panic!("expected a unique violation"),
+    }
+    collection.add(doc!("name" => "John", "age" => 40)).unwrap();
+    collection.add(doc!("name" => "John")).unwrap();
+    assert_eq!(collection.count(), 5);
+
+    let age = collection.get(john).unwrap().get("age").unwrap().to_number();
+    let other = if age == 30 { 25 } else { 30 };
+    assert!(matches!(collection.update_document(john, doc!("age" => other)), Err(DbError::UniqueViolation { .. })));
+    assert_eq!(collection.get(john).unwrap().get("age").unwrap().to_number(), age);
+    let update = Update::from_json(&format!(r#"{{"$set": {{"age": {}}}}}"#, other)).unwrap();
+    assert!(matches!(collection.patch_document(john, &update), Err(DbError::UniqueViolation { .. })));
+    let update = Update::from_json(r#"{"$inc": {"name": 1}}"#).unwrap();
+    assert!(matches!(collection.patch_document(john, &update), Err(DbError::InvalidUpdate(_))));
+    collection.update_document(john, doc!("age" => 50)).unwrap();
+  }
+
+  #[test]
+  fn replace_and_upsert() {
+    let mut collection = users();
+    let jane = collection.find(HashMap::from([("name".to_string(), DataType::from("Jane"))]))[0].get("ID").unwrap().to_id();
+    let etag = collection.etag(jane).unwrap();
+    let (document, added) = collection.replace_document(jane, doc!("name" => "Jane Doe"), true).unwrap();
+    assert!(!added);
+    assert_eq!(document.len(), 2);
+    assert_eq!(document.get("ID").unwrap().to_id(), jane);
+    assert_ne!(collection.etag(jane).unwrap(), etag);
+
+    // a reverted write gives back the etag before it, a document added again with the same ID gets a new one
+    let etag = collection.etag(jane).unwrap();
+    let saved = collection.saved(jane).unwrap();
+    let old = saved.0.clone();
+    collection.update_document(jane, doc!("age" => 40)).unwrap();
+    collection.update_document(jane, doc!("age" => 41)).unwrap();
+    let updated = collection.etag(jane).unwrap();
+    collection.revert(jane, saved);
+    assert_eq!(collection.etag(jane).unwrap(), etag);
+    collection.rm(jane).unwrap();
+    collection.add(old).unwrap();
+    assert!(collection.etag(jane).unwrap() != updated && collection.etag(jane).unwrap() != etag);
+
+    let id = Uuid::new_v4();
+    assert!(matches!(collection.replace_document(id, doc!("name" => "Doe"), false), Err(DbError::DocumentNotFound)));
+    let count = collection.count();
+    let (_, added) = collection.replace_document(id, doc!("name" => "Doe"), true).unwrap();
+    assert!(added);
+    assert_eq!(collection.count(), count + 1);
+    assert_eq!(collection.get(id).unwrap().get("name").unwrap().to_string(), "Doe");
+  }
+
+  #[test]
+  fn update_and_delete_many() {
+    let mut collection = users();
+    let all = Filter::And(Vec::new());
+    let johns = Filter::from_json(r#"{"name": "John"}"#).unwrap();
+    let update = Update::from_json(r#"{"$set": {"age": 30}}"#).unwrap();
+    let result = collection.update_many(&johns, &update, true).unwrap();
+    assert_eq!((result.matched, result.ids.len()), (2, 1));
+    assert_eq!(names(collection.query(&all)), vec!["Jane 30", "John 25", "John 30"]);
+    collection.update_many(&johns, &update, false).unwrap();
+    assert_eq!(names(collection.query(&all)), vec!["Jane 30", "John 30", "John 30"]);
+
+    // a document failing the update writes nothing
+    let id = collection.add(doc!("name" => "Doe", "age" => "old")).unwrap();
+    let inc = Update::from_json(r#"{"$inc": {"age": 1}}"#).unwrap();
+    assert!(matches!(collection.update_many(&all, &inc, false), Err(DbError::InvalidUpdate(_))));
+    assert_eq!(names(collection.query(&johns)), vec!["John 30", "John 30"]);
+    collection.rm(id).unwrap();
+
+    // two documents of the batch can't take the same values of a unique index
+    let mut people = Collection::new("people".to_string());
+    people.create_index(IndexSpec::new("email", &["email"], true)).unwrap();
+    people.add(doc!("email" => "a")).unwrap();
+    people.add(doc!("email" => "b")).unwrap();
+    let same = Update::from_json(r#"{"$set": {"email": "c"}}"#).unwrap();
+    assert!(matches!(people.update_many(&all, &same, false), Err(DbError::UniqueViolation { .. })));
+    let emails: Vec<String> = people.query(&all).iter().map(|x| x.get("email").unwrap().to_string()).collect();
+    assert_eq!(emails, vec!["a", "b"]);
+
+    let older = Filter::from_json(r#"{"age": {"$gte": 30}, "name": "John"}"#).unwrap();
+    let deleted = collection.delete_many(&older, true).unwrap();
+    assert_eq!(deleted.ids.len(), 2);
+    assert_eq!(collection.count(), 3);
+    collection.delete_many(&older, false).unwrap();
+    assert_eq!(names(collection.query(&all)), vec!["Jane 30"]);
+  }
+
+  #[test]
+  fn find_by_id() {
+    let collection = users();
+    let john = collection.find(HashMap::from([("age".to_string(), DataType::from(25))]))[0];
+    let id = john.get("ID").unwrap().to_string();
+    let args = HashMap::from([("ID".to_string(), DataType::from(id.clone()))]);
+    assert_eq!(names(collection.find(args)), vec!["John 25"]);
+    let args = HashMap::from([("ID".to_string(), DataType::from(id)), ("age".to_string(), DataType::from(30))]);
+    assert!(collection.find(args).is_empty());
+  }
+
+  // Any value that can be read from JSON, integers in the range of i32 are always Numbers
+  fn value() -> impl Strategy<Value = DataType> {
+    let leaf = prop_oneof![
+      Just(DataType::Null),
+      any::<bool>().prop_map(DataType::Boolean),
+      any::<i32>().prop_map(DataType::Number),
+      any::<i64>().prop_filter("out of i32", |x| i32::try_from(*x).is_err()).prop_map(DataType::Int),
+      any::<f64>().prop_filter("finite", |x| x.is_finite()).prop_map(DataType::Float),
+      ".*".prop_map(DataType::Text),
+    ];
+    leaf.prop_recursive(4, 64, 8, |inner| prop_oneof![
+      prop::collection::vec(inner.clone(), 0..8).prop_map(DataType::Array),
+      prop::collection::hash_map(".*", inner, 0..8).prop_map(DataType::Document),
+    ])
+  }
+
+  fn document() -> impl Strategy<Value = Document> {
+    prop::collection::hash_map(".*", value(), 0..8).prop_map(|mut document| {
+      document.remove("ID");
+      document
+    })
+  }
+
+  // DataType compares numbers across types, the serialized form tells them apart
+  fn exact(document: &Document) -> Value {
+    serde_json::to_value(document).unwrap()
+  }
+
+  proptest! {
+    #[test]
+    fn json_round_trip(mut document in document(), id in any::<u128>()) {
+      document.insert("ID".to_string(), DataType::Id(Uuid::from_u128(id)));
+      let back = Document::from_json(&document.to_json());
+      prop_assert_eq!(exact(&back), exact(&document));
+    }
+
+    #[test]
+    fn add_and_get_round_trip(document in document()) {
+      let json = document.to_json();
+      let mut collection = Collection::new("nested".to_string());
+      let id = collection.add(Document::from_json(&json)).unwrap();
+      let mut stored: Value = serde_json::from_str(&collection.get(id).unwrap().to_json()).unwrap();
+      stored.as_object_mut().unwrap().remove("ID");
+      prop_assert_eq!(stored, serde_json::from_str::<Value>(&json).unwrap());
+    }
+  }
+
+  #[test]
+  fn nested_json() {
+    let json = r#"{"tags": ["a", 1, 2.5, null, [true]], "address": {"city": "Madrid", "geo": {"lat": 40.4}}}"#;
+    let document = Document::from_json(json);
+    assert_eq!(document.get("tags").unwrap().to_array().len(), 5);
+    assert_eq!(document.get("address").unwrap().to_document().get("city").unwrap().to_text(), "Madrid");
+    assert_eq!(serde_json::from_str::<Value>(&document.to_json()).unwrap(), serde_json::from_str::<Value>(json).unwrap());
+  }
 }
@@ -0,0 +1,96 @@
+// The cursor module encodes where a page of documents ends in a continuation token
+// A token holds the sort of the query, the sort values of the last document returned and its
+// sequence number, the order in which it was added to the collection
+// The next page starts right after that position, so the documents added or removed
+// in the meantime don't shift the pages
+//
+// Tokens are JSON encoded as hexadecimal text, opaque to the clients and safe in a URL
+
+use std::fmt::Write;
+use serde::{Deserialize, Serialize};
+use super::data_type::DataType;
+use super::index::{IndexKey, KeyPart};
+use super::sort::Sort;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Cursor {
+    // the sort of the query, empty for the insertion order
+    #[serde(default)]
+    sort: String,
+    #[serde(default)]
+    key: Vec<Option<DataType>>,
+    seq: u64,
+}
+
+fn sort_text(sort: Option<&Sort>) -> String {
+    sort.map(|sort| sort.to_string()).unwrap_or_default()
+}
+
+impl Cursor {
+    pub fn new(sort: Option<&Sort>, key: IndexKey, seq: u64) -> Cursor {
+        Cursor {
+            sort: sort_text(sort),
+            key: key.0.into_iter().map(|part| part.value).collect(),
+            seq,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap();
+        let mut token = String::with_capacity(json.len() * 2);
+        for byte in json {
+            write!(token, "{:02x}", byte).unwrap();
+        }
+        token
+    }
+
+    // Read a token given with the same sort of the query that returned it
+    pub fn decode(token: &str, sort: Option<&Sort>) -> Result<Cursor, String> {
+        let invalid = || "Invalid cursor".to_string();
+        if !token.len().is_multiple_of(2) || !token.is_ascii() {
+            return Err(invalid());
+        }
+        let bytes = (0..token.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&token[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid())?;
+        let cursor: Cursor = serde_json::from_slice(&bytes).map_err(|_| invalid())?;
+        if cursor.sort != sort_text(sort) || cursor.key.len() != sort.map_or(0, |sort| sort.fields.len()) {
+            return Err("The cursor belongs to a query with a different sort".to_string());
+        }
+        Ok(cursor)
+    }
+
+    // The position of the last document of the page, compared with the positions of the documents
+    pub fn position(&self, sort: Option<&Sort>) -> (IndexKey, u64) {
+        let fields = sort.map(|sort| sort.fields.as_slice()).unwrap_or_default();
+        let key = self.key.iter().zip(fields).map(|(value, field)| KeyPart {
+            value: value.clone(),
+            descending: field.descending,
+        });
+        (IndexKey(key.collect()), self.seq)
+    }
+}
+
+
+//TEST
+#[cfg(test)]
+mod tests {
+    use crate::doc;
+    use crate::memodb::sort::Sort;
+    use super::Cursor;
+
+    #[test]
+    fn encode_and_decode() {
+        let sort = Sort::parse("age,-name").unwrap();
+        let cursor = Cursor::new(Some(&sort), sort.key(&doc!{"age" => 30}), 12);
+        let token = cursor.encode();
+        assert!(token.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(Cursor::decode(&token, Some(&sort)).unwrap(), cursor);
+        assert!(Cursor::decode(&token, None).is_err());
+        assert!(Cursor::decode(&token, Some(&Sort::parse("age").unwrap())).is_err());
+        assert!(Cursor::decode("zz", None).is_err());
+        assert!(Cursor::decode(&token[1..], Some(&sort)).is_err());
+    }
+}
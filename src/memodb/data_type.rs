@@ -3,10 +3,13 @@
 // this will be store several types of data, like text, numbers, dates, arrays and documents
 //
 // The data type will be used to store the data in the documents
+use std::cmp::Ordering;
 use uuid::Uuid;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use super::collection::{Document, DocumentJson};
 
-#[derive(PartialEq)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum DataType {
   Id(Uuid),
   Text(String),
@@ -14,6 +17,44 @@ pub enum DataType {
   Boolean(bool),
   Array(Vec<DataType>),
   Document(Document),
+  // integers out of the range of Number
+  Int(i64),
+  Float(f64),
+  Null,
+}
+
+// A numeric value of any of the numeric types
+#[derive(Clone, Copy)]
+enum Numeric {
+  Int(i64),
+  Float(f64),
+}
+
+impl Numeric {
+  // Exact order of two numbers, None if any of them is NaN
+  fn compare(self, other: Numeric) -> Option<Ordering> {
+    match (self, other) {
+      (Numeric::Int(a), Numeric::Int(b)) => Some(a.cmp(&b)),
+      (Numeric::Float(a), Numeric::Float(b)) => a.partial_cmp(&b),
+      (Numeric::Int(a), Numeric::Float(b)) => Numeric::compare_int_float(a, b),
+      (Numeric::Float(a), Numeric::Int(b)) => Numeric::compare_int_float(b, a).map(Ordering::reverse),
+    }
+  }
+
+  // i64 can't be converted to f64 without losing precision, compare the integer part first
+  fn compare_int_float(a: i64, b: f64) -> Option<Ordering> {
+    if b.is_nan() {
+      return None;
+    }
+    if b >= 9223372036854775808.0 {
+      return Some(Ordering::Less);
+    }
+    if b < -9223372036854775808.0 {
+      return Some(Ordering::Greater);
+    }
+    let integer = b.trunc();
+    Some(a.cmp(&(integer as i64)).then_with(|| 0.0.partial_cmp(&(b - integer)).unwrap()))
+  }
 }
 
 impl DataType {
@@ -21,39 +62,44 @@ impl DataType {
     match self {
       DataType::Id(_) => "id",
       DataType::Text(_) => "text",
-      DataType::Number(_) => "number",
+      DataType::Number(_) | DataType::Int(_) | DataType::Float(_) => "number",
       DataType::Boolean(_) => "boolean",
       DataType::Array(_) => "array",
       DataType::Document(_) => "document",
+      DataType::Null => "null",
     }
   }
 
-  #[deprecated]
-  pub fn to_json(&self) -> String {
+  fn numeric(&self) -> Option<Numeric> {
     match self {
-      DataType::Id(id) => format!("\"{}\"", id.to_string()),
-      DataType::Text(text) => format!("\"{}\"", text),
-      DataType::Number(number) => number.to_string(),
-      DataType::Boolean(boolean) => boolean.to_string(),
-      DataType::Array(array) => {
-        let mut json = String::from("[");
-        for value in array {
-          json.push_str(&value.to_json());
-          json.push_str(",");
-        }
-        json.pop();
-        json.push(']');
-        json
-      }
-      DataType::Document(document) => {
-        let mut json = String::from("{");
-        for (key, value) in document {
-          json.push_str(&format!("\"{}\":{},", key, value.to_json()));
-        }
-        json.pop();
-        json.push('}');
-        json
-      }
+      DataType::Number(number) => Some(Numeric::Int(*number as i64)),
+      DataType::Int(number) => Some(Numeric::Int(*number)),
+      DataType::Float(number) => Some(Numeric::Float(*number)),
+      _ => None,
+    }
+  }
+
+  // The integer of a Number or an Int, None for the floats and the other types
+  pub fn as_integer(&self) -> Option<i64> {
+    match self.numeric() {
+      Some(Numeric::Int(number)) => Some(number),
+      _ => None,
+    }
+  }
+
+  // Any number as a float
+  pub fn as_float(&self) -> Option<f64> {
+    match self.numeric()? {
+      Numeric::Int(number) => Some(number as f64),
+      Numeric::Float(number) => Some(number),
+    }
+  }
+
+  // Keep the integers that fit in a Number as a Number
+  pub fn integer(number: i64) -> DataType {
+    match i32::try_from(number) {
+      Ok(number) => DataType::Number(number),
+      Err(_) => DataType::Int(number),
     }
   }
 
@@ -77,13 +123,123 @@ impl DataType {
       DataType::Boolean(true)
     } else if json == "false" {
       DataType::Boolean(false)
+    } else if json == "null" {
+      DataType::Null
+    } else if let Ok(number) = json.parse::<i64>() {
+      DataType::integer(number)
     } else {
-      match json.parse::<i32>() {
-        Ok(number) => DataType::Number(number),
-        Err(_) => DataType::Text(json.to_string()),
+      match json.parse::<f64>() {
+        Ok(number) if number.is_finite() => DataType::Float(number),
+        _ => DataType::Text(json.to_string()),
+      }
+    }
+  }
+  // Convert a parsed JSON value, used for the values of the queries
+  pub fn from_value(value: &Value) -> DataType {
+    match value {
+      Value::Number(n) => match n.as_i64() {
+        Some(number) => DataType::integer(number),
+        // floats and integers over i64::MAX
+        None => DataType::Float(n.as_f64().unwrap_or(f64::NAN)),
+      },
+      Value::String(s) => DataType::Text(s.clone()),
+      Value::Bool(b) => DataType::Boolean(*b),
+      Value::Array(array) => DataType::Array(array.iter().map(DataType::from_value).collect()),
+      Value::Object(object) => DataType::Document(
+        object.iter().map(|(k, v)| (k.clone(), DataType::from_value(v))).collect()
+      ),
+      Value::Null => DataType::Null,
+    }
+  }
+
+  // Convert to a JSON value, the inverse of from_value
+  // IDs are written as text, NaN and the infinities as null
+  pub fn to_value(&self) -> Value {
+    match self {
+      DataType::Id(id) => Value::String(id.to_string()),
+      DataType::Text(text) => Value::String(text.clone()),
+      DataType::Number(number) => Value::from(*number),
+      DataType::Int(number) => Value::from(*number),
+      DataType::Float(number) => Value::from(*number),
+      DataType::Boolean(boolean) => Value::Bool(*boolean),
+      DataType::Null => Value::Null,
+      DataType::Array(array) => Value::Array(array.iter().map(DataType::to_value).collect()),
+      DataType::Document(document) => Value::Object(
+        document.iter().map(|(k, v)| (k.clone(), v.to_value())).collect()
+      ),
+    }
+  }
+
+  // Order two values of the same type, used by the range operators of the queries
+  // values of different types or not orderable (arrays and documents) return None
+  // all the numeric types are compared as numbers, so 3 == 3.0
+  // dates are stored as ISO 8601 text, so they are ordered as text
+  pub fn compare(&self, other: &DataType) -> Option<Ordering> {
+    if let (Some(a), Some(b)) = (self.numeric(), other.numeric()) {
+      return a.compare(b);
+    }
+    match (self, other) {
+      (DataType::Null, DataType::Null) => Some(Ordering::Equal),
+      (DataType::Text(a), DataType::Text(b)) => Some(a.cmp(b)),
+      (DataType::Boolean(a), DataType::Boolean(b)) => Some(a.cmp(b)),
+      (DataType::Id(a), DataType::Id(b)) => Some(a.cmp(b)),
+      _ => None,
+    }
+  }
+
+  // Position of the type when values of different types are ordered
+  fn type_rank(&self) -> u8 {
+    match self {
+      DataType::Null => 0,
+      DataType::Number(_) | DataType::Int(_) | DataType::Float(_) => 1,
+      DataType::Text(_) => 2,
+      DataType::Document(_) => 3,
+      DataType::Array(_) => 4,
+      DataType::Id(_) => 5,
+      DataType::Boolean(_) => 6,
+    }
+  }
+
+  // Total order of all the values, used by the indexes
+  // values of different types are ordered by type: null < numbers < text < documents < arrays < ids < booleans
+  // NaN goes before the rest of the numbers
+  // arrays are ordered element by element, and documents by their keys and values in key order
+  pub fn total_cmp(&self, other: &DataType) -> Ordering {
+    match (self, other) {
+      (DataType::Array(a), DataType::Array(b)) => {
+        for (x, y) in a.iter().zip(b.iter()) {
+          let ordering = x.total_cmp(y);
+          if ordering.is_ne() {
+            return ordering;
+          }
+        }
+        a.len().cmp(&b.len())
       }
+      (DataType::Document(a), DataType::Document(b)) => {
+        let mut a: Vec<(&String, &DataType)> = a.iter().collect();
+        let mut b: Vec<(&String, &DataType)> = b.iter().collect();
+        a.sort_by(|x, y| x.0.cmp(y.0));
+        b.sort_by(|x, y| x.0.cmp(y.0));
+        for ((ka, va), (kb, vb)) in a.iter().zip(b.iter()) {
+          let ordering = ka.cmp(kb).then_with(|| va.total_cmp(vb));
+          if ordering.is_ne() {
+            return ordering;
+          }
+        }
+        a.len().cmp(&b.len())
+      }
+      _ => match self.compare(other) {
+        Some(ordering) => ordering,
+        None => match (self, other) {
+          (DataType::Float(a), DataType::Float(b)) if a.is_nan() && b.is_nan() => Ordering::Equal,
+          (DataType::Float(a), _) if a.is_nan() && other.numeric().is_some() => Ordering::Less,
+          (_, DataType::Float(b)) if b.is_nan() && self.numeric().is_some() => Ordering::Greater,
+          _ => self.type_rank().cmp(&other.type_rank()),
+        },
+      },
     }
   }
+
   //add into 
   pub fn to_id(&self) -> Uuid {
     match self {
@@ -130,6 +286,9 @@ impl ToString for DataType {
       DataType::Id(id) => id.to_string(),
       DataType::Text(text) => text.to_string(),
       DataType::Number(number) => number.to_string(),
+      DataType::Int(number) => number.to_string(),
+      DataType::Float(number) => number.to_string(),
+      DataType::Null => "null".to_string(),
       DataType::Boolean(boolean) => boolean.to_string(),
       DataType::Array(array) => {
         let mut result = String::new();
@@ -178,6 +337,18 @@ impl From<i32> for DataType {
   }
 }
 
+impl From<i64> for DataType {
+  fn from(value: i64) -> Self {
+    DataType::Int(value)
+  }
+}
+
+impl From<f64> for DataType {
+  fn from(value: f64) -> Self {
+    DataType::Float(value)
+  }
+}
+
 impl From<bool> for DataType {
   fn from(value: bool) -> Self {
     DataType::Boolean(value)
@@ -206,6 +377,66 @@ impl Clone for DataType {
       DataType::Boolean(boolean) => DataType::Boolean(*boolean),
       DataType::Array(array) => DataType::Array(array.clone()),
       DataType::Document(document) => DataType::Document(document.clone()),
+      DataType::Int(number) => DataType::Int(*number),
+      DataType::Float(number) => DataType::Float(*number),
+      DataType::Null => DataType::Null,
+    }
+  }
+}
+
+// Numbers are equal when they have the same value, whatever their type
+impl PartialEq for DataType {
+  fn eq(&self, other: &Self) -> bool {
+    if let (Some(a), Some(b)) = (self.numeric(), other.numeric()) {
+      return a.compare(b) == Some(Ordering::Equal);
     }
+    match (self, other) {
+      (DataType::Id(a), DataType::Id(b)) => a == b,
+      (DataType::Text(a), DataType::Text(b)) => a == b,
+      (DataType::Boolean(a), DataType::Boolean(b)) => a == b,
+      (DataType::Array(a), DataType::Array(b)) => a == b,
+      (DataType::Document(a), DataType::Document(b)) => a == b,
+      (DataType::Null, DataType::Null) => true,
+      _ => false,
+    }
+  }
+}
+
+//TEST
+#[cfg(test)]
+mod tests {
+  use std::cmp::Ordering;
+  use serde_json::json;
+  use crate::doc;
+  use crate::memodb::collection::DocumentJson;
+  use super::DataType;
+
+  #[test]
+  fn numbers_compare_across_types() {
+    assert_eq!(DataType::from(3), DataType::from(3.0));
+    assert_eq!(DataType::from(3i64), DataType::from(3));
+    assert_ne!(DataType::from(3), DataType::from(3.5));
+    assert_eq!(DataType::from(3).compare(&DataType::from(3.5)), Some(Ordering::Less));
+    assert_eq!(DataType::from(i64::MAX).compare(&DataType::from(9223372036854775807.0)), Some(Ordering::Less));
+    assert_eq!(DataType::from(f64::NAN).compare(&DataType::from(1)), None);
+    assert_eq!(DataType::from(f64::NAN).total_cmp(&DataType::from(f64::NEG_INFINITY)), Ordering::Less);
+    assert_eq!(DataType::Null.total_cmp(&DataType::from(f64::NAN)), Ordering::Less);
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn numbers_from_json() {
+    assert!(matches!(DataType::from_value(&json!(5)), DataType::Number(5)));
+    assert!(matches!(DataType::from_value(&json!(5_000_000_000i64)), DataType::Int(5_000_000_000)));
+    assert!(matches!(DataType::from_value(&json!(2.5)), DataType::Float(x) if x == 2.5));
+    assert!(matches!(DataType::from_value(&json!(null)), DataType::Null));
+
+    let document = doc!("int" => 9007199254740993i64, "float" => 3.0, "null" => DataType::Null);
+    let json = document.to_json();
+    let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(value, json!({"int": 9007199254740993i64, "float": 3.0, "null": null}));
+    let back = super::Document::from_json(&json);
+    assert!(matches!(back.get("int"), Some(DataType::Int(9007199254740993))));
+    assert!(matches!(back.get("float"), Some(DataType::Float(x)) if *x == 3.0));
+    assert!(matches!(back.get("null"), Some(DataType::Null)));
+  }
+}
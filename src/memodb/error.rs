@@ -0,0 +1,47 @@
+// Errors returned by the MEMOdb operations
+
+use std::fmt;
+use std::io;
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub enum DbError {
+    CollectionExists,
+    CollectionNotFound,
+    DocumentNotFound,
+    // a document with the same ID is already in the collection
+    DocumentExists,
+    IndexExists,
+    IndexNotFound,
+    // the write would give two documents the same values in a unique index,
+    // `id` is the document already using those values
+    UniqueViolation { index: String, id: Uuid },
+    // an update operator can't be applied to the document, like $inc on a text
+    InvalidUpdate(String),
+    // the write could not be stored in the write-ahead log, nothing was changed
+    Io(io::Error),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DbError::CollectionExists => write!(f, "Collection already exists"),
+            DbError::CollectionNotFound => write!(f, "Collection not found"),
+            DbError::DocumentNotFound => write!(f, "Document not found"),
+            DbError::DocumentExists => write!(f, "Document already exists"),
+            DbError::IndexExists => write!(f, "Index already exists"),
+            DbError::IndexNotFound => write!(f, "Index not found"),
+            DbError::UniqueViolation { index, id } => {
+                write!(f, "Unique index {} violated, the values are used by document {}", index, id)
+            }
+            DbError::InvalidUpdate(e) => write!(f, "Invalid update: {}", e),
+            DbError::Io(e) => write!(f, "IO error: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for DbError {
+    fn from(value: io::Error) -> Self {
+        DbError::Io(value)
+    }
+}
@@ -1,65 +1,474 @@
 // Written by Alberto Ruiz 2024-03-08
-// [Unfinished] module to index and create search structures
+// Module to index and create search structures
 //
 // The B-tree will be used to index the documents
 // and provide a fast search
+//
+// Every key of the tree stores the IDs of the documents with that value,
+// the tree keeps itself balanced: full nodes are split on insert and
+// nodes with too few keys borrow from a sibling or are merged on delete
+//
+// The nodes and the IDs are shared between the clones of a tree, a write copies only
+// the nodes on the path to the key it changes, so cloning a tree costs the same at any size
 
+use std::ops::Bound;
+use std::sync::Arc;
+use uuid::Uuid;
+
+// minimum degree of the tree, every node but the root has between T-1 and 2T-1 keys
+const T: usize = 16;
 
 // B-tree node
 //      v: value of the content to index
-//      p: position of the document in the collection
-struct MIndex {
-    pub v: i32,
-    pub p: Vec<i32>,
+//      p: IDs of the documents with that value
+#[derive(Clone)]
+pub struct MIndex<K> {
+    pub v: K,
+    pub p: Arc<Vec<Uuid>>,
+}
+
+impl<K> MIndex<K> {
+    fn add(&mut self, id: Uuid) {
+        if !self.p.contains(&id) {
+            Arc::make_mut(&mut self.p).push(id);
+        }
+    }
 }
 
 //B-tree node
-pub struct BNode {
-    keys: Vec<MIndex>,
-    children: Vec<Box<BNode>>,
+#[derive(Clone)]
+pub struct BNode<K> {
+    keys: Vec<MIndex<K>>,
+    children: Vec<Arc<BNode<K>>>,
 }
 
-impl BNode {
-    pub fn new() -> BNode {
+impl<K: Ord + Clone> BNode<K> {
+    pub fn new() -> BNode<K> {
         BNode {
             keys: Vec::new(),
             children: Vec::new(),
         }
     }
 
-    fn _search(node: &BNode, value: i32) -> Option<Vec<i32>> {
-        let mut i = 0;
-        while i < node.keys.len() && value > node.keys[i].v {
-            i += 1;
+    fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    fn is_full(&self) -> bool {
+        self.keys.len() == 2 * T - 1
+    }
+
+    fn position(&self, value: &K) -> Result<usize, usize> {
+        self.keys.binary_search_by(|key| key.v.cmp(value))
+    }
+
+    fn _search<'a>(node: &'a BNode<K>, value: &K) -> Option<&'a Vec<Uuid>> {
+        match node.position(value) {
+            Ok(i) => Some(&node.keys[i].p),
+            Err(i) if !node.is_leaf() => BNode::_search(&node.children[i], value),
+            Err(_) => None,
         }
-        if i < node.keys.len() && value == node.keys[i].v {
-            return Some(node.keys[i].p.clone());
+    }
+
+    pub fn search(&self, value: &K) -> Option<&Vec<Uuid>> {
+        BNode::_search(self, value)
+    }
+
+    // the child i to write, copied first when another tree shares it
+    fn child_mut(&mut self, i: usize) -> &mut BNode<K> {
+        Arc::make_mut(&mut self.children[i])
+    }
+
+    // split the full child i, its median key moves up to this node
+    fn split_child(&mut self, i: usize) {
+        let child = self.child_mut(i);
+        let keys = child.keys.split_off(T);
+        let children = if child.is_leaf() { Vec::new() } else { child.children.split_off(T) };
+        let median = child.keys.pop().unwrap();
+        self.keys.insert(i, median);
+        self.children.insert(i + 1, Arc::new(BNode { keys, children }));
+    }
+
+    // insert in a node that is not full
+    fn insert_non_full(&mut self, value: K, id: Uuid) {
+        let mut i = match self.position(&value) {
+            Ok(i) => {
+                self.keys[i].add(id);
+                return;
+            }
+            Err(i) => i,
+        };
+        if self.is_leaf() {
+            self.keys.insert(i, MIndex { v: value, p: Arc::new(vec![id]) });
+            return;
         }
-        if node.children.len() > 0 {
-            return BNode::_search(&node.children[i], value);
+        if self.children[i].is_full() {
+            self.split_child(i);
+            if value == self.keys[i].v {
+                self.keys[i].add(id);
+                return;
+            }
+            if value > self.keys[i].v {
+                i += 1;
+            }
         }
-        None
+        self.child_mut(i).insert_non_full(value, id)
     }
 
-    pub fn search(&self, value: i32) -> Option<Vec<i32>> {
-        BNode::_search(&self, value)
+    // make sure child i has at least T keys before going down into it
+    // returns the position of the child, that moves if it is merged with its left sibling
+    fn fill(&mut self, i: usize) -> usize {
+        if self.children[i].keys.len() >= T {
+            return i;
+        }
+        if i > 0 && self.children[i - 1].keys.len() >= T {
+            // borrow from the left sibling
+            let (left, right) = self.children.split_at_mut(i);
+            let left = Arc::make_mut(&mut left[i - 1]);
+            let child = Arc::make_mut(&mut right[0]);
+            let borrowed = left.keys.pop().unwrap();
+            let separator = std::mem::replace(&mut self.keys[i - 1], borrowed);
+            child.keys.insert(0, separator);
+            if !left.is_leaf() {
+                child.children.insert(0, left.children.pop().unwrap());
+            }
+            i
+        } else if i < self.keys.len() && self.children[i + 1].keys.len() >= T {
+            // borrow from the right sibling
+            let (left, right) = self.children.split_at_mut(i + 1);
+            let child = Arc::make_mut(&mut left[i]);
+            let right = Arc::make_mut(&mut right[0]);
+            let borrowed = right.keys.remove(0);
+            let separator = std::mem::replace(&mut self.keys[i], borrowed);
+            child.keys.push(separator);
+            if !right.is_leaf() {
+                child.children.push(right.children.remove(0));
+            }
+            i
+        } else if i < self.keys.len() {
+            self.merge(i);
+            i
+        } else {
+            self.merge(i - 1);
+            i - 1
+        }
     }
 
-    pub fn insert(&mut self, value: i32, pos: i32) {
-        let mut i = 0;
-        while i < self.keys.len() && value > self.keys[i].v {
-            i += 1;
+    // merge child i + 1 and the key i into child i
+    fn merge(&mut self, i: usize) {
+        let right = Arc::unwrap_or_clone(self.children.remove(i + 1));
+        let separator = self.keys.remove(i);
+        let child = self.child_mut(i);
+        child.keys.push(separator);
+        child.keys.extend(right.keys);
+        child.children.extend(right.children);
+    }
+
+    fn remove_min(&mut self) -> MIndex<K> {
+        if self.is_leaf() {
+            return self.keys.remove(0);
         }
-        if i < self.keys.len() && value == self.keys[i].v {
-            self.keys[i].p.push(pos);
-            return;
+        let i = self.fill(0);
+        self.child_mut(i).remove_min()
+    }
+
+    fn remove_max(&mut self) -> MIndex<K> {
+        if self.is_leaf() {
+            return self.keys.pop().unwrap();
+        }
+        let i = self.fill(self.children.len() - 1);
+        self.child_mut(i).remove_max()
+    }
+
+    // remove the whole key, the node has at least T keys unless it is the root
+    fn remove_key(&mut self, value: &K) -> Option<MIndex<K>> {
+        match self.position(value) {
+            Ok(i) if self.is_leaf() => Some(self.keys.remove(i)),
+            Ok(i) => {
+                if self.children[i].keys.len() >= T {
+                    let predecessor = self.child_mut(i).remove_max();
+                    Some(std::mem::replace(&mut self.keys[i], predecessor))
+                } else if self.children[i + 1].keys.len() >= T {
+                    let successor = self.child_mut(i + 1).remove_min();
+                    Some(std::mem::replace(&mut self.keys[i], successor))
+                } else {
+                    self.merge(i);
+                    self.child_mut(i).remove_key(value)
+                }
+            }
+            Err(_) if self.is_leaf() => None,
+            Err(i) => {
+                let i = self.fill(i);
+                self.child_mut(i).remove_key(value)
+            }
+        }
+    }
+}
+
+impl<K: Ord + Clone> Default for BNode<K> {
+    fn default() -> Self {
+        BNode::new()
+    }
+}
+
+// Balanced B-tree from values to the IDs of the documents with that value
+#[derive(Clone)]
+pub struct BTree<K> {
+    root: BNode<K>,
+}
+
+impl<K: Ord + Clone> BTree<K> {
+    pub fn new() -> BTree<K> {
+        BTree { root: BNode::new() }
+    }
+
+    pub fn search(&self, value: &K) -> Option<&Vec<Uuid>> {
+        self.root.search(value)
+    }
+
+    pub fn insert(&mut self, value: K, id: Uuid) {
+        if self.root.is_full() {
+            let old_root = std::mem::take(&mut self.root);
+            self.root.children.push(Arc::new(old_root));
+            self.root.split_child(0);
+        }
+        self.root.insert_non_full(value, id);
+    }
+
+    // remove the ID from the value, the value is removed when it has no IDs left
+    pub fn remove(&mut self, value: &K, id: Uuid) {
+        match self.search(value) {
+            Some(ids) if ids.contains(&id) => {}
+            _ => return,
         }
-        if self.children.len() > 0 {
-            self.children[i].insert(value, pos);
+        let ids = Arc::make_mut(self.search_mut(value).unwrap());
+        ids.retain(|x| *x != id);
+        if !ids.is_empty() {
             return;
         }
-        self.keys.insert(i, MIndex { v: value, p: vec![pos] });
+        self.root.remove_key(value);
+        if self.root.keys.is_empty() && !self.root.is_leaf() {
+            self.root = Arc::unwrap_or_clone(self.root.children.remove(0));
+        }
     }
 
+    // the IDs of the value to write, the nodes on the way are copied when they are shared
+    fn search_mut(&mut self, value: &K) -> Option<&mut Arc<Vec<Uuid>>> {
+        let mut node = &mut self.root;
+        loop {
+            match node.position(value) {
+                Ok(i) => return Some(&mut node.keys[i].p),
+                Err(i) if !node.is_leaf() => node = node.child_mut(i),
+                Err(_) => return None,
+            }
+        }
+    }
 
-}
\ No newline at end of file
+    // Iterate in order over the values from `start`
+    pub fn iter_from(&self, start: Bound<&K>) -> Iter<'_, K> {
+        let mut stack = Vec::new();
+        let mut node = &self.root;
+        loop {
+            let (i, found) = match start {
+                Bound::Unbounded => (0, false),
+                Bound::Included(value) => match node.position(value) {
+                    Ok(i) => (i, true),
+                    Err(i) => (i, false),
+                },
+                Bound::Excluded(value) => match node.position(value) {
+                    Ok(i) => (i + 1, false),
+                    Err(i) => (i, false),
+                },
+            };
+            stack.push((node, i));
+            if found || node.is_leaf() {
+                break;
+            }
+            node = &node.children[i];
+        }
+        Iter { stack }
+    }
+
+    pub fn iter(&self) -> Iter<'_, K> {
+        self.iter_from(Bound::Unbounded)
+    }
+}
+
+impl<K: Ord + Clone> Default for BTree<K> {
+    fn default() -> Self {
+        BTree::new()
+    }
+}
+
+pub struct Iter<'a, K> {
+    // nodes being visited and the position of their next key
+    stack: Vec<(&'a BNode<K>, usize)>,
+}
+
+impl<'a, K> Iterator for Iter<'a, K> {
+    type Item = &'a MIndex<K>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, i)) = self.stack.pop() {
+            if i < node.keys.len() {
+                self.stack.push((node, i + 1));
+                if !node.children.is_empty() {
+                    let mut child = &node.children[i + 1];
+                    loop {
+                        self.stack.push((child, 0));
+                        if child.children.is_empty() {
+                            break;
+                        }
+                        child = &child.children[0];
+                    }
+                }
+                return Some(&node.keys[i]);
+            }
+        }
+        None
+    }
+}
+
+
+//TEST
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashSet};
+    use std::ops::Bound;
+    use std::sync::Arc;
+    use uuid::Uuid;
+    use super::{BNode, BTree, T};
+
+    // check the B-tree properties, returns the depth of the node
+    fn check<K: Ord + Clone>(node: &BNode<K>, is_root: bool) -> usize {
+        assert!(node.keys.len() < 2 * T);
+        if !is_root {
+            assert!(node.keys.len() >= T - 1);
+        }
+        assert!(node.keys.windows(2).all(|w| w[0].v < w[1].v));
+        if node.is_leaf() {
+            return 1;
+        }
+        assert_eq!(node.children.len(), node.keys.len() + 1);
+        let depths: Vec<usize> = node.children.iter().map(|child| check(child, false)).collect();
+        assert!(depths.iter().all(|depth| *depth == depths[0]));
+        for (i, key) in node.keys.iter().enumerate() {
+            assert!(node.children[i].keys.last().unwrap().v < key.v);
+            assert!(node.children[i + 1].keys[0].v > key.v);
+        }
+        depths[0] + 1
+    }
+
+    // xorshift, enough to shuffle the test values
+    fn random(seed: &mut u64) -> u64 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        *seed
+    }
+
+    #[test]
+    fn insert_search_and_remove() {
+        let mut tree = BTree::new();
+        let mut expected: BTreeMap<i64, Vec<Uuid>> = BTreeMap::new();
+        let mut seed = 42;
+        for _ in 0..5000 {
+            let value = (random(&mut seed) % 1000) as i64;
+            let id = Uuid::new_v4();
+            tree.insert(value, id);
+            expected.entry(value).or_default().push(id);
+        }
+        check(&tree.root, true);
+        assert_eq!(tree.iter().count(), expected.len());
+        for (value, ids) in expected.iter() {
+            assert_eq!(tree.search(value), Some(ids));
+        }
+        assert!(tree.search(&1000).is_none());
+
+        let values: Vec<i64> = expected.keys().cloned().collect();
+        for value in values {
+            if random(&mut seed) % 3 == 1 {
+                continue;
+            }
+            for id in expected.remove(&value).unwrap() {
+                tree.remove(&value, id);
+            }
+            check(&tree.root, true);
+        }
+        assert_eq!(tree.iter().count(), expected.len());
+        let in_order: Vec<i64> = tree.iter().map(|key| key.v).collect();
+        assert_eq!(in_order, expected.keys().cloned().collect::<Vec<i64>>());
+
+        for (value, ids) in expected.iter() {
+            for id in ids {
+                tree.remove(value, *id);
+            }
+        }
+        assert!(tree.iter().next().is_none());
+        assert!(tree.root.keys.is_empty() && tree.root.is_leaf());
+    }
+
+    #[test]
+    fn remove_one_of_many_ids() {
+        let mut tree = BTree::new();
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        tree.insert("x", a);
+        tree.insert("x", b);
+        tree.insert("x", b);
+        assert_eq!(tree.search(&"x"), Some(&vec![a, b]));
+        tree.remove(&"x", a);
+        assert_eq!(tree.search(&"x"), Some(&vec![b]));
+        tree.remove(&"x", b);
+        assert!(tree.search(&"x").is_none());
+    }
+
+    // pointers to every node under `node`
+    fn nodes<K>(node: &BNode<K>, out: &mut HashSet<*const BNode<K>>) {
+        for child in node.children.iter() {
+            out.insert(Arc::as_ptr(child));
+            nodes(child, out);
+        }
+    }
+
+    #[test]
+    fn clones_share_nodes() {
+        let mut tree = BTree::new();
+        for value in 0..5000 {
+            tree.insert(value, Uuid::new_v4());
+        }
+        let before = tree.clone();
+        tree.insert(2500, Uuid::new_v4());
+        tree.remove(&10, before.search(&10).unwrap()[0]);
+        check(&tree.root, true);
+        assert_eq!(before.search(&2500).unwrap().len(), 1);
+        assert_eq!(tree.search(&2500).unwrap().len(), 2);
+        assert!(before.search(&10).is_some() && tree.search(&10).is_none());
+        // only the nodes on the way to the two values were copied
+        let (mut old, mut new) = (HashSet::new(), HashSet::new());
+        nodes(&before.root, &mut old);
+        nodes(&tree.root, &mut new);
+        assert!(old.len() > 300);
+        assert!(new.difference(&old).count() <= 8);
+    }
+
+    #[test]
+    fn range_scan() {
+        let mut tree = BTree::new();
+        let mut seed = 7;
+        let mut values: Vec<i64> = (0..2000).collect();
+        for i in (1..values.len()).rev() {
+            values.swap(i, (random(&mut seed) % (i as u64 + 1)) as usize);
+        }
+        for value in values {
+            tree.insert(value * 2, Uuid::new_v4());
+        }
+        let scan = |start, n| tree.iter_from(start).take(n).map(|key| key.v).collect::<Vec<i64>>();
+        assert_eq!(scan(Bound::Included(&100), 3), vec![100, 102, 104]);
+        assert_eq!(scan(Bound::Excluded(&100), 3), vec![102, 104, 106]);
+        assert_eq!(scan(Bound::Included(&101), 2), vec![102, 104]);
+        assert_eq!(scan(Bound::Excluded(&3994), 3), vec![3996, 3998]);
+        assert_eq!(scan(Bound::Unbounded, 2), vec![0, 2]);
+        assert_eq!(scan(Bound::Included(&4000), 1), Vec::<i64>::new());
+        assert_eq!(tree.iter().count(), 2000);
+    }
+}
@@ -0,0 +1,390 @@
+// The index module will provide the secondary indexes of the collections
+// An index maps the values of one or more fields to the IDs of the documents with those values,
+// it is stored in a B-tree so it can answer the equality and range conditions of the queries
+//
+// Every field of an index is ordered ascending or descending, so a scan of the index returns
+// the documents already ordered by its fields
+// Every document is indexed, a missing field goes before any value (after them if descending),
+// documents missing any of the fields never violate a unique index
+//
+// The fields can be dot paths, and a field holding an array indexes each of its elements,
+// so a document can have several keys; such an index is multikey and a scan can find
+// a document more than once, the duplicates are dropped
+
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::Bound;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use super::collection::Document;
+use super::data_type::DataType;
+use super::finder::BTree;
+use super::path;
+use super::query::Condition;
+
+// Value of one field of an index, None if the document doesn't have the field
+#[derive(Clone)]
+pub struct KeyPart {
+    pub value: Option<DataType>,
+    pub descending: bool,
+}
+
+impl KeyPart {
+    fn cmp_value(&self, other: Option<&DataType>) -> Ordering {
+        let ordering = match (&self.value, other) {
+            (None, None) => Ordering::Equal,
+            (None, Some(_)) => Ordering::Less,
+            (Some(_), None) => Ordering::Greater,
+            (Some(a), Some(b)) => a.total_cmp(b),
+        };
+        if self.descending { ordering.reverse() } else { ordering }
+    }
+}
+
+// Values of the fields of an index, ordered field by field with the total order of DataType
+#[derive(Clone)]
+pub struct IndexKey(pub Vec<KeyPart>);
+
+impl PartialEq for IndexKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for IndexKey {}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        for (a, b) in self.0.iter().zip(other.0.iter()) {
+            let ordering = a.cmp_value(b.value.as_ref());
+            if ordering.is_ne() {
+                return ordering;
+            }
+        }
+        // a prefix goes before the keys that start with it
+        self.0.len().cmp(&other.0.len())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IndexField {
+    pub name: String,
+    #[serde(default)]
+    pub descending: bool,
+}
+
+impl IndexField {
+    // "field" is ascending and "-field" descending
+    pub fn parse(field: &str) -> IndexField {
+        match field.strip_prefix('-') {
+            Some(name) => IndexField { name: name.to_string(), descending: true },
+            None => IndexField { name: field.to_string(), descending: false },
+        }
+    }
+}
+
+impl fmt::Display for IndexField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.descending {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", self.name)
+    }
+}
+
+// Definition of an index, stored in the snapshots and the write-ahead log
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IndexSpec {
+    pub name: String,
+    pub fields: Vec<IndexField>,
+    // no two documents can have the same values in the fields
+    #[serde(default)]
+    pub unique: bool,
+}
+
+impl IndexSpec {
+    pub fn new(name: &str, fields: &[&str], unique: bool) -> IndexSpec {
+        IndexSpec { name: name.to_string(), fields: fields.iter().map(|field| IndexField::parse(field)).collect(), unique }
+    }
+}
+
+#[derive(Clone)]
+pub struct Index {
+    pub spec: IndexSpec,
+    tree: BTree<IndexKey>,
+    // some document has more than one key
+    pub multikey: bool,
+}
+
+// Smallest value with the type of `value`, where the range of values comparable to it starts
+fn type_start(value: &DataType) -> DataType {
+    match value {
+        DataType::Id(_) => DataType::Id(Uuid::nil()),
+        DataType::Text(_) => DataType::Text(String::new()),
+        // NaN is before the rest of the numbers but never in a range
+        DataType::Number(_) | DataType::Int(_) | DataType::Float(_) => DataType::Float(f64::NEG_INFINITY),
+        DataType::Boolean(_) => DataType::Boolean(false),
+        DataType::Array(_) => DataType::Array(Vec::new()),
+        DataType::Document(_) => DataType::Document(Document::new()),
+        DataType::Null => DataType::Null,
+    }
+}
+
+impl Index {
+    pub fn new(spec: IndexSpec) -> Index {
+        Index { spec, tree: BTree::new(), multikey: false }
+    }
+
+    // Every combination of the values of the fields in the document,
+    // and whether any of the fields had an array or more than one value
+    fn keys(&self, document: &Document) -> (Vec<IndexKey>, bool) {
+        let mut keys = vec![Vec::new()];
+        let mut multikey = false;
+        for field in self.spec.fields.iter() {
+            let found = path::values(document, &field.name);
+            multikey |= found.len() > 1;
+            let mut values: Vec<Option<DataType>> = Vec::new();
+            for value in found {
+                match value {
+                    DataType::Array(items) => {
+                        multikey = true;
+                        values.extend(items.iter().cloned().map(Some));
+                    }
+                    value => values.push(Some(value.clone())),
+                }
+            }
+            if values.is_empty() {
+                values.push(None);
+            }
+            keys = keys.into_iter().flat_map(|key: Vec<KeyPart>| values.iter().map(move |value| {
+                let mut key = key.clone();
+                key.push(KeyPart { value: value.clone(), descending: field.descending });
+                key
+            })).collect();
+        }
+        let mut keys: Vec<IndexKey> = keys.into_iter().map(IndexKey).collect();
+        keys.sort();
+        keys.dedup();
+        (keys, multikey)
+    }
+
+    fn part(&self, position: usize, value: &DataType) -> KeyPart {
+        KeyPart { value: Some(value.clone()), descending: self.spec.fields[position].descending }
+    }
+
+    // ID of another document with the same values, if the index is unique
+    pub fn conflict(&self, id: Uuid, document: &Document) -> Option<Uuid> {
+        if !self.spec.unique {
+            return None;
+        }
+        self.keys(document).0
+            .iter()
+            .filter(|key| key.0.iter().all(|part| part.value.is_some()))
+            .find_map(|key| self.tree.search(key)?.iter().find(|other| **other != id).cloned())
+    }
+
+    pub fn insert(&mut self, id: Uuid, document: &Document) {
+        let (keys, multikey) = self.keys(document);
+        self.multikey |= multikey;
+        for key in keys {
+            self.tree.insert(key, id);
+        }
+    }
+
+    pub fn remove(&mut self, id: Uuid, document: &Document) {
+        for key in self.keys(document).0 {
+            self.tree.remove(&key, id);
+        }
+    }
+
+    // IDs of the documents whose first fields are equal to `prefix` and whose next field
+    // matches all the `range` conditions ($gt, $gte, $lt, $lte), in the order of the index
+    pub fn scan(&self, prefix: &[DataType], range: &[&Condition]) -> Vec<Uuid> {
+        let mut seen = HashSet::new();
+        self.scan_groups(prefix, range)
+            .into_iter()
+            .flatten()
+            .filter(|id| !self.multikey || seen.insert(*id))
+            .collect()
+    }
+
+    // Same as scan, with the IDs grouped by key
+    pub fn scan_groups(&self, prefix: &[DataType], range: &[&Condition]) -> Vec<Vec<Uuid>> {
+        let k = prefix.len();
+        let mut start: Vec<KeyPart> = prefix.iter().enumerate().map(|(i, value)| self.part(i, value)).collect();
+        let mut lower = None;
+        let mut upper = None;
+        for condition in range {
+            match condition {
+                Condition::Gt(value) | Condition::Gte(value) => lower = Some(value),
+                Condition::Lt(value) | Condition::Lte(value) => upper = Some(value),
+                _ => {}
+            }
+        }
+        // only values of the same type are compared, the range ends with the type
+        let reference = lower.or(upper);
+        if let Some(reference) = reference {
+            if reference.compare(reference).is_none() {
+                // arrays and documents are not comparable
+                return Vec::new();
+            }
+            let descending = self.spec.fields[k].descending;
+            let first = if descending { upper.cloned() } else { Some(lower.cloned().unwrap_or(type_start(reference))) };
+            if let Some(first) = first {
+                start.push(self.part(k, &first));
+            }
+        }
+        let past_end = |value: Option<&DataType>| -> bool {
+            let (reference, descending) = match reference {
+                Some(reference) => (reference, self.spec.fields[k].descending),
+                None => return false,
+            };
+            let value = match value {
+                Some(value) => value,
+                // missing values are after the range when descending
+                None => return true,
+            };
+            let (end, after) = if descending { (lower, Ordering::Less) } else { (upper, Ordering::Greater) };
+            match end {
+                Some(end) => value.total_cmp(end) == after,
+                None => value.compare(reference).is_none() && value.total_cmp(reference) == after,
+            }
+        };
+        self.tree
+            .iter_from(Bound::Included(&IndexKey(start)))
+            .take_while(|key| {
+                let parts = &key.v.0;
+                let in_prefix = prefix.iter().enumerate().all(|(i, value)| parts[i].cmp_value(Some(value)).is_eq());
+                in_prefix && (k == parts.len() || !past_end(parts[k].value.as_ref()))
+            })
+            .filter(|key| k == key.v.0.len() || range.iter().all(|condition| condition.matches(key.v.0[k].value.as_ref())))
+            .map(|key| Vec::clone(&key.p))
+            .collect()
+    }
+
+    // Every value of the first field once, in the order of the index
+    pub fn first_values(&self) -> Vec<DataType> {
+        let mut values: Vec<DataType> = Vec::new();
+        for key in self.tree.iter() {
+            match &key.v.0[0].value {
+                Some(value) if values.last().is_none_or(|last| last.total_cmp(value).is_ne()) => values.push(value.clone()),
+                _ => {}
+            }
+        }
+        values
+    }
+
+    // IDs of the documents whose first field is any of `values`
+    pub fn scan_in(&self, values: &[DataType]) -> Vec<Uuid> {
+        let mut seen = HashSet::new();
+        let mut ids = Vec::new();
+        for value in values {
+            for id in self.scan(std::slice::from_ref(value), &[]) {
+                if seen.insert(id) {
+                    ids.push(id);
+                }
+            }
+        }
+        ids
+    }
+}
+
+
+//TEST
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+    use crate::doc;
+    use crate::memodb::data_type::DataType;
+    use crate::memodb::query::Condition;
+    use super::{Index, IndexSpec};
+
+    #[test]
+    fn index_conditions() {
+        let mut index = Index::new(IndexSpec::new("age", &["age"], false));
+        let ids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+        index.insert(ids[0], &doc!{"age" => 30});
+        index.insert(ids[1], &doc!{"age" => 25});
+        index.insert(ids[2], &doc!{"age" => "thirty"});
+        index.insert(ids[3], &doc!{"name" => "John"});
+
+        let scan = |range: Condition| index.scan(&[], &[&range]);
+        assert_eq!(index.scan(&[DataType::from(30)], &[]), vec![ids[0]]);
+        assert_eq!(scan(Condition::Gte(DataType::from(25))), vec![ids[1], ids[0]]);
+        assert_eq!(scan(Condition::Lt(DataType::from(30))), vec![ids[1]]);
+        assert_eq!(scan(Condition::Gt(DataType::from("a"))), vec![ids[2]]);
+        assert_eq!(index.scan_in(&[DataType::from(25), DataType::from("thirty"), DataType::from(25)]), vec![ids[1], ids[2]]);
+        // the documents without the field are indexed too
+        assert_eq!(index.scan(&[], &[]), vec![ids[3], ids[1], ids[0], ids[2]]);
+
+        index.remove(ids[1], &doc!{"age" => 25});
+        assert_eq!(index.scan(&[], &[&Condition::Lte(DataType::from(30))]), vec![ids[0]]);
+    }
+
+    #[test]
+    fn compound_index_order() {
+        let mut index = Index::new(IndexSpec::new("tenant_created", &["tenant", "-created"], false));
+        let ids: Vec<Uuid> = (0..6).map(|_| Uuid::new_v4()).collect();
+        index.insert(ids[0], &doc!{"tenant" => "a", "created" => 1});
+        index.insert(ids[1], &doc!{"tenant" => "a", "created" => 3});
+        index.insert(ids[2], &doc!{"tenant" => "b", "created" => 2});
+        index.insert(ids[3], &doc!{"tenant" => "a", "created" => 2});
+        index.insert(ids[4], &doc!{"tenant" => "a"});
+        index.insert(ids[5], &doc!{"tenant" => "a", "created" => "yesterday"});
+
+        let a = [DataType::from("a")];
+        // newest first, the documents without the field go last
+        assert_eq!(index.scan(&a, &[]), vec![ids[5], ids[1], ids[3], ids[0], ids[4]]);
+        let gte = Condition::Gte(DataType::from(2));
+        assert_eq!(index.scan(&a, &[&gte]), vec![ids[1], ids[3]]);
+        let lt = Condition::Lt(DataType::from(3));
+        assert_eq!(index.scan(&a, &[&gte, &lt]), vec![ids[3]]);
+        assert_eq!(index.scan(&a, &[&lt]), vec![ids[3], ids[0]]);
+        assert_eq!(index.scan(&[DataType::from("a"), DataType::from(2)], &[]), vec![ids[3]]);
+    }
+
+    #[test]
+    fn multikey_index() {
+        let mut index = Index::new(IndexSpec::new("tags_city", &["tags", "address.city"], false));
+        let ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let tagged = |tags: Vec<&str>, city: &str| {
+            let tags: Vec<DataType> = tags.into_iter().map(DataType::from).collect();
+            doc!{"tags" => tags, "address" => doc!{"city" => city}}
+        };
+        index.insert(ids[0], &tagged(vec!["rust", "db"], "Madrid"));
+        assert!(index.multikey);
+        index.insert(ids[1], &tagged(vec!["rust", "rust"], "Paris"));
+        index.insert(ids[2], &doc!{"tags" => "db"});
+
+        assert_eq!(index.scan(&[DataType::from("rust")], &[]), vec![ids[0], ids[1]]);
+        assert_eq!(index.scan(&[DataType::from("db")], &[]), vec![ids[2], ids[0]]);
+        assert_eq!(index.scan(&[DataType::from("rust"), DataType::from("Paris")], &[]), vec![ids[1]]);
+        // each document is returned once
+        assert_eq!(index.scan(&[], &[&Condition::Gte(DataType::from("a"))]).len(), 3);
+
+        index.remove(ids[0], &tagged(vec!["rust", "db"], "Madrid"));
+        assert_eq!(index.scan(&[DataType::from("rust")], &[]), vec![ids[1]]);
+    }
+
+    #[test]
+    fn unique_compound_index() {
+        let mut index = Index::new(IndexSpec::new("tenant_email", &["tenant", "email"], true));
+        let ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        index.insert(ids[0], &doc!{"tenant" => "a", "email" => "john@a.com"});
+        index.insert(ids[1], &doc!{"tenant" => "b", "email" => "john@a.com"});
+        index.insert(ids[2], &doc!{"tenant" => "a"});
+
+        assert_eq!(index.conflict(ids[2], &doc!{"tenant" => "a", "email" => "john@a.com"}), Some(ids[0]));
+        assert_eq!(index.conflict(ids[0], &doc!{"tenant" => "a", "email" => "john@a.com"}), None);
+        assert_eq!(index.conflict(ids[2], &doc!{"tenant" => "c", "email" => "john@a.com"}), None);
+        // documents without all the fields never conflict
+        assert_eq!(index.conflict(Uuid::new_v4(), &doc!{"tenant" => "a"}), None);
+    }
+}
@@ -3,57 +3,179 @@
 // it will store the data in memory and provide a simple API to interact with it
 //
 // The MEMOdb will have a collection of documents, each document will be a HashMap<String, DataType>
+//
+// Every collection has its own lock, a write only waits for the other writes on the same collection
+// and the reads take a version of the collection that the writes don't change (see mvcc)
+// The locks are always taken in the same order, the list of collections before a collection
+// and the collections in the order of the list, so two requests never wait for each other
     
 
+pub mod aggregate;
+pub mod bulk;
 pub mod collection;
+pub mod cursor;
 pub mod data_type;
+pub mod error;
 mod finder;
+pub mod index;
+pub mod mvcc;
+pub mod path;
+pub mod query;
+mod snapshot;
+pub mod sort;
+pub mod transaction;
+pub mod update;
+pub mod wal;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
 use collection::Collection;
+use error::DbError;
+use mvcc::VersionedCollection;
+use wal::{Record, SyncPolicy, Wal};
 
 pub struct MEMOdb {
     pub version: &'static str,
-    collections: Vec<Collection>,
+    // the name is kept out of the lock, finding a collection never waits for a write
+    collections: RwLock<Vec<(String, Arc<VersionedCollection>)>>,
+    snapshot_path: Option<PathBuf>,
+    wal: Option<Arc<Wal>>,
+    // one save at a time, they write the same files
+    saving: Mutex<()>,
 }
 
 impl MEMOdb {
     pub fn new() -> Self {
         MEMOdb {
             version: "0.1.5",
-            collections: Vec::new(),
+            collections: RwLock::new(Vec::new()),
+            snapshot_path: None,
+            wal: None,
+            saving: Mutex::new(()),
+        }
+    }
+
+    // Open a database persisted in `snapshot_path`, starting empty if there is no snapshot yet,
+    // and replay the writes of the log in `wal_path` made after that snapshot
+    pub fn open(snapshot_path: &Path, wal_path: &Path, sync: SyncPolicy) -> io::Result<Self> {
+        let mut db = MEMOdb::new();
+        let lsn = match snapshot::load(snapshot_path) {
+            Ok((collections, lsn)) => {
+                db.collections = RwLock::new(collections.into_iter().map(|x| (x.name.clone(), Arc::new(VersionedCollection::new(x)))).collect());
+                lsn
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        let (wal, records) = Wal::open(wal_path, sync, lsn)?;
+        for record in records {
+            db.replay(record);
+        }
+        for (_, collection) in db.collections.get_mut().unwrap().iter() {
+            collection.write().wal = Some(wal.clone());
+        }
+        db.snapshot_path = Some(snapshot_path.to_path_buf());
+        db.wal = Some(wal);
+        Ok(db)
+    }
+
+    fn replay(&mut self, record: Record) {
+        let collections = self.collections.get_mut().unwrap();
+        match record {
+            Record::CreateCollection { name } => {
+                if !collections.iter().any(|(x, _)| *x == name) {
+                    collections.push((name.clone(), Arc::new(VersionedCollection::new(Collection::new(name)))));
+                }
+            }
+            Record::RemoveCollection { name } => collections.retain(|(x, _)| *x != name),
+            Record::Batch { records } => {
+                for record in records {
+                    self.replay(record);
+                }
+            }
+            Record::Add { ref collection, .. }
+            | Record::Remove { ref collection, .. }
+            | Record::Update { ref collection, .. }
+            | Record::Replace { ref collection, .. }
+            | Record::CreateIndex { ref collection, .. }
+            | Record::DropIndex { ref collection, .. } => {
+                match collections.iter().find(|(x, _)| x == collection) {
+                    Some((_, collection)) => collection.write().replay(record),
+                    None => eprintln!("Skipping write to unknown collection {}", collection),
+                }
+            }
+        }
+    }
+
+    fn log(&self, record: Record) -> Result<(), DbError> {
+        if let Some(wal) = &self.wal {
+            wal.append(&record)?;
+        }
+        Ok(())
+    }
+
+    // Write a snapshot of all collections and drop the log records it contains,
+    // does nothing for databases not opened from a path
+    pub fn save(&self) -> io::Result<()> {
+        let path = match &self.snapshot_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let _saving = self.saving.lock().unwrap();
+        // the writes wait while the lsn and the versions to save are taken, not while they are written
+        let (lsn, versions) = {
+            let collections = self.collections.read().unwrap();
+            let writers: Vec<_> = collections.iter().map(|(_, x)| x.write()).collect();
+            let lsn = self.wal.as_ref().map_or(0, |wal| wal.last_lsn());
+            (lsn, writers.iter().map(|x| x.snapshot()).collect::<Vec<_>>())
+        };
+        let collections: Vec<&Collection> = versions.iter().map(|x| &**x).collect();
+        snapshot::save(path, self.version, lsn, &collections)?;
+        if let Some(wal) = &self.wal {
+            wal.compact(lsn)?;
         }
+        Ok(())
     }
 
-    pub fn create_collection(&mut self, name: String) -> Result<(), &str>{
+    pub fn create_collection(&self, name: String) -> Result<(), DbError> {
+        let mut collections = self.collections.write().unwrap();
         //check if collection exists
-        if self.collections.iter().any(|x| x.name == name) {
-            Err("Collection already exists")
+        if collections.iter().any(|(x, _)| *x == name) {
+            Err(DbError::CollectionExists)
         } else {
-            let collection = Collection::new(name);
-            self.collections.push(collection);
+            self.log(Record::CreateCollection { name: name.clone() })?;
+            let mut collection = Collection::new(name.clone());
+            collection.wal = self.wal.clone();
+            collections.push((name, Arc::new(VersionedCollection::new(collection))));
             Ok(())
         }
     }
 
-    pub fn get_collection(&mut self, name: String) -> Option<&mut Collection> {
-        //return a mutable reference to collection
-        self.collections.iter_mut().find(|x| x.name == name)
+    // The caller takes a snapshot of the collection to read it, or its writer to write it
+    pub fn get_collection(&self, name: String) -> Option<Arc<VersionedCollection>> {
+        let collections = self.collections.read().unwrap();
+        collections.iter().find(|(x, _)| *x == name).map(|(_, collection)| collection.clone())
     }
 
     pub fn get_collection_list(&self) -> Vec<String> {
         let mut collection_list: Vec<String> = Vec::new();
-        for collection in self.collections.iter() {
-            collection_list.push(collection.name.clone());
+        for (name, _) in self.collections.read().unwrap().iter() {
+            collection_list.push(name.clone());
         }
         collection_list
     }
 
-    pub fn remove_collection(&mut self, name: String) -> Collection {
-        let index = self
-            .collections
+    pub fn remove_collection(&self, name: String) -> Result<Arc<VersionedCollection>, DbError> {
+        let mut collections = self.collections.write().unwrap();
+        let index = collections
             .iter()
-            .position(|x| x.name == name)
-            .unwrap();
-        self.collections.remove(index)
+            .position(|(x, _)| *x == name)
+            .ok_or(DbError::CollectionNotFound)?;
+        self.log(Record::RemoveCollection { name })?;
+        let (_, collection) = collections.remove(index);
+        // a request still holding the collection can't log writes to it anymore
+        collection.write().wal = None;
+        Ok(collection)
     }
 
 }
@@ -66,6 +188,9 @@ mod tests {
     use crate::memodb::collection::Document;
     use crate::memodb::collection::DocumentStruct;
     use crate::memodb::collection::DocumentJson;
+    use crate::memodb::query::Filter;
+    use crate::memodb::update::Update;
+    use crate::memodb::wal::SyncPolicy;
 
     struct User {
         name: String,
@@ -89,30 +214,31 @@ mod tests {
 
     #[test]
     fn test_memodb() {
-        let mut memodb = crate::memodb::MEMOdb::new();
+        let memodb = crate::memodb::MEMOdb::new();
         let r1 = memodb.create_collection("users".to_string()).is_ok();
         let r2 = memodb.create_collection("posts".to_string()).is_ok();
         assert!(r1);
         assert!(r2);
-        assert_eq!(memodb.collections.len(), 2);
-        assert_eq!(memodb.collections[0].name, "users");
-        assert_eq!(memodb.collections[1].name, "posts");
-        assert_eq!(memodb.get_collection("users".to_string()).unwrap().name, "users");
-        assert_eq!(memodb.get_collection("posts".to_string()).unwrap().name, "posts");
+        assert_eq!(memodb.collections.read().unwrap().len(), 2);
+        assert_eq!(memodb.collections.read().unwrap()[0].0, "users");
+        assert_eq!(memodb.collections.read().unwrap()[1].0, "posts");
+        assert_eq!(memodb.get_collection("users".to_string()).unwrap().snapshot().name, "users");
+        assert_eq!(memodb.get_collection("posts".to_string()).unwrap().snapshot().name, "posts");
         assert_eq!(memodb.get_collection_list().len(), 2);
-        assert_eq!(memodb.remove_collection("users".to_string()).name, "users");
-        assert_eq!(memodb.collections.len(), 1);
-        assert_eq!(memodb.remove_collection("posts".to_string()).name, "posts");
-        assert_eq!(memodb.collections.len(), 0);
+        assert_eq!(memodb.remove_collection("users".to_string()).unwrap().snapshot().name, "users");
+        assert_eq!(memodb.collections.read().unwrap().len(), 1);
+        assert_eq!(memodb.remove_collection("posts".to_string()).unwrap().snapshot().name, "posts");
+        assert_eq!(memodb.collections.read().unwrap().len(), 0);
     }
 
     #[test]
     fn add_document() {
-        let mut memodb = crate::memodb::MEMOdb::new();
+        let memodb = crate::memodb::MEMOdb::new();
         let _ = memodb.create_collection("users".to_string());
-        let collection = memodb.get_collection("users".to_string()).unwrap();
-        let id1 = collection.add(doc!{"name" => "John", "age" => 30});
-        let id2 = collection.add(doc!{"name" => "Jane", "age" => 25});
+        let users = memodb.get_collection("users".to_string()).unwrap();
+        let mut collection = users.write();
+        let id1 = collection.add(doc!{"name" => "John", "age" => 30}).unwrap();
+        let id2 = collection.add(doc!{"name" => "Jane", "age" => 25}).unwrap();
         assert_eq!(collection.count(), 2);
         let document = collection.get(id1).unwrap();
         let user = User::from_document(document);
@@ -121,14 +247,15 @@ mod tests {
 
     #[test]
     fn add_document_from_struct() {
-        let mut memodb = crate::memodb::MEMOdb::new();
+        let memodb = crate::memodb::MEMOdb::new();
         let _ = memodb.create_collection("users".to_string());
-        let collection = memodb.get_collection("users".to_string()).unwrap();
+        let users = memodb.get_collection("users".to_string()).unwrap();
+        let mut collection = users.write();
         let user = User {
             name: "John".to_string(),
             age: 30,
         };
-        let id = collection.add(user.to_document());
+        let id = collection.add(user.to_document()).unwrap();
         assert_eq!(collection.count(), 1);
         let document = collection.get(id).unwrap();
         document.to_json();
@@ -136,4 +263,130 @@ mod tests {
         assert_eq!(user.name, "John");
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn reopen_from_snapshot_and_log() {
+        let dir = std::env::temp_dir();
+        let snapshot_path = dir.join(format!("memodb-{}.json", uuid::Uuid::new_v4()));
+        let wal_path = dir.join(format!("memodb-{}.wal", uuid::Uuid::new_v4()));
+        let open = || crate::memodb::MEMOdb::open(&snapshot_path, &wal_path, SyncPolicy::Always).unwrap();
+
+        let memodb = open();
+        assert_eq!(memodb.get_collection_list().len(), 0);
+        let _ = memodb.create_collection("users".to_string());
+        let users = memodb.get_collection("users".to_string()).unwrap();
+        let mut collection = users.write();
+        let id = collection.add(doc!{"name" => "John", "age" => 30}).unwrap();
+        collection.add(doc!{"name" => "Jane", "age" => 25}).unwrap();
+        drop(collection);
+        memodb.save().unwrap();
+        assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), 0);
+        let mut collection = users.write();
+        collection.update_document(id, doc!{"age" => 31}).unwrap();
+        let update = Update::from_json(r#"{"$set": {"address.city": "Madrid"}, "$push": {"tags": "rust"}}"#).unwrap();
+        collection.patch_document(id, &update).unwrap();
+        let all = Filter::And(Vec::new());
+        collection.update_many(&all, &Update::from_json(r#"{"$set": {"active": true}}"#).unwrap(), false).unwrap();
+        collection.delete_many(&Filter::from_json(r#"{"name": "Jane"}"#).unwrap(), false).unwrap();
+        drop(collection);
+        let _ = memodb.create_collection("posts".to_string());
+        drop(memodb);
+
+        // the last writes are only in the log
+        let memodb = open();
+        assert_eq!(memodb.get_collection_list(), vec!["users", "posts"]);
+        let users = memodb.get_collection("users".to_string()).unwrap();
+        let collection = users.snapshot();
+        assert_eq!(collection.count(), 1);
+        let user = User::from_document(collection.get(id).unwrap());
+        assert_eq!(user.age, 31);
+        let document = collection.get(id).unwrap().to_json();
+        assert!(document.contains(r#""address":{"city":"Madrid"}"#) && document.contains(r#""tags":["rust"]"#));
+        assert!(document.contains(r#""active":true"#));
+        drop(collection);
+        memodb.remove_collection("posts".to_string()).unwrap();
+        memodb.save().unwrap();
+        drop(memodb);
+
+        let memodb = open();
+        std::fs::remove_file(&snapshot_path).unwrap();
+        std::fs::remove_file(&wal_path).unwrap();
+        assert_eq!(memodb.get_collection_list(), vec!["users"]);
+        let users = memodb.get_collection("users".to_string()).unwrap();
+        assert_eq!(User::from_document(users.snapshot().get(id).unwrap()).age, 31);
+    }
+
+    // Throughput of full scans of a collection while a writer updates it,
+    // as the number of reading threads grows
+    // cargo test --release -- --ignored --nocapture read_throughput
+    #[test]
+    #[ignore]
+    fn read_throughput() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::{Duration, Instant};
+
+        let memodb = Arc::new(crate::memodb::MEMOdb::new());
+        memodb.create_collection("users".to_string()).unwrap();
+        let users = memodb.get_collection("users".to_string()).unwrap();
+        for i in 0..10_000 {
+            users.write().add(doc!{"name" => format!("user {}", i), "age" => i % 100}).unwrap();
+        }
+        let id = users.snapshot().query(&Filter::And(Vec::new()))[0].get("ID").unwrap().to_id();
+        let duration = Duration::from_secs(2);
+        let update = Update::from_json(r#"{"$inc": {"visits": 1}}"#).unwrap();
+        let start = Instant::now();
+        let mut writes = 0u64;
+        while start.elapsed() < duration {
+            users.write().patch_document(id, &update).unwrap();
+            writes += 1;
+        }
+        let alone = writes as f64 / start.elapsed().as_secs_f64();
+        println!("no readers: {:.0} writes/s", alone);
+        let mut single = 0.0;
+        for threads in [1, 2, 4, 8] {
+            let stop = Arc::new(AtomicBool::new(false));
+            let writer = {
+                let (memodb, stop) = (memodb.clone(), stop.clone());
+                std::thread::spawn(move || {
+                    let users = memodb.get_collection("users".to_string()).unwrap();
+                    let update = Update::from_json(r#"{"$inc": {"visits": 1}}"#).unwrap();
+                    let mut writes = 0u64;
+                    while !stop.load(Ordering::Relaxed) {
+                        users.write().patch_document(id, &update).unwrap();
+                        writes += 1;
+                    }
+                    writes
+                })
+            };
+            let start = Instant::now();
+            let readers: Vec<_> = (0..threads).map(|_| {
+                let memodb = memodb.clone();
+                std::thread::spawn(move || {
+                    let filter = Filter::from_json(r#"{"age": {"$gte": 50}}"#).unwrap();
+                    let mut reads = 0u64;
+                    while start.elapsed() < duration {
+                        let users = memodb.get_collection("users".to_string()).unwrap();
+                        assert_eq!(users.snapshot().query(&filter).len(), 5_000);
+                        reads += 1;
+                    }
+                    reads
+                })
+            }).collect();
+            let reads: u64 = readers.into_iter().map(|x| x.join().unwrap()).sum();
+            stop.store(true, Ordering::Relaxed);
+            let writes = writer.join().unwrap();
+            let elapsed = start.elapsed().as_secs_f64();
+            let per_second = reads as f64 / elapsed;
+            if threads == 1 {
+                single = per_second;
+            }
+            let writes_per_second = writes as f64 / elapsed;
+            println!("{} threads: {:.0} reads/s, {:.2}x, {:.0} writes/s", threads, per_second, per_second / single, writes_per_second);
+            // a write doesn't copy the collection the readers hold, it only shares the cpu with them
+            let cores = std::thread::available_parallelism().map_or(1, |x| x.get());
+            let share = (cores as f64 / (threads + 1) as f64).min(1.0);
+            assert!(writes_per_second > alone * share / 4.0, "{:.0} writes/s with {} readers, {:.0} alone", writes_per_second, threads, alone);
+        }
+    }
+
+}
@@ -0,0 +1,169 @@
+// The mvcc module keeps the versions of a collection shared by the requests
+// A reader takes the current version and reads it without holding any lock, the writes
+// made after that don't change it, so a long query or export sees the collection as it was
+// when it started
+// A writer works on its own copy of the current version, the readers see its writes only once
+// it is dropped, so readers and writers never wait for each other
+// A writer dropped by a panic is thrown away, its writes may be half done or not logged
+// The copy shares the documents, the id table and the index nodes with the previous version,
+// a write copies only what it changes, O(log n) of the collection
+// A version is freed when the last reader holding it is done with it
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use super::collection::Collection;
+
+pub struct VersionedCollection {
+    // the version the readers get, replaced when a writer is dropped
+    current: Mutex<Arc<Collection>>,
+    // held by the writer, one at a time
+    writing: Mutex<()>,
+}
+
+impl VersionedCollection {
+    pub fn new(collection: Collection) -> Self {
+        VersionedCollection { current: Mutex::new(Arc::new(collection)), writing: Mutex::new(()) }
+    }
+
+    // The collection as it is now, the writes made after this don't change it
+    // The lock is held only to clone the Arc, never while a write runs
+    pub fn snapshot(&self) -> Arc<Collection> {
+        self.current.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    // Write the collection, one writer at a time
+    // The snapshots taken while the writer is alive see the version before it
+    // A request panicking with the writer doesn't make the collection unusable for the next ones
+    pub fn write(&self) -> Writer<'_> {
+        let writing = self.writing.lock().unwrap_or_else(PoisonError::into_inner);
+        Writer { versions: self, collection: self.snapshot(), _writing: writing }
+    }
+}
+
+pub struct Writer<'a> {
+    versions: &'a VersionedCollection,
+    collection: Arc<Collection>,
+    _writing: MutexGuard<'a, ()>,
+}
+
+impl Writer<'_> {
+    // The collection with the writes made so far
+    pub fn snapshot(&self) -> Arc<Collection> {
+        self.collection.clone()
+    }
+}
+
+impl Deref for Writer<'_> {
+    type Target = Collection;
+
+    fn deref(&self) -> &Collection {
+        &self.collection
+    }
+}
+
+impl DerefMut for Writer<'_> {
+    // the first write copies the version, the readers still hold it
+    fn deref_mut(&mut self) -> &mut Collection {
+        Arc::make_mut(&mut self.collection)
+    }
+}
+
+impl Drop for Writer<'_> {
+    // publish the writes, after a panic the previous version is kept
+    fn drop(&mut self) {
+        if thread::panicking() {
+            return;
+        }
+        *self.versions.current.lock().unwrap_or_else(PoisonError::into_inner) = self.collection.clone();
+    }
+}
+
+//TEST
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use crate::doc;
+    use crate::memodb::collection::Collection;
+    use crate::memodb::query::Filter;
+    use crate::memodb::update::Update;
+    use super::VersionedCollection;
+
+    #[test]
+    fn snapshots_and_old_versions() {
+        let users = VersionedCollection::new(Collection::new("users".to_string()));
+        let id = users.write().add(doc!{"name" => "John"}).unwrap();
+        let before = users.snapshot();
+        users.write().update_document(id, doc!{"name" => "Jane"}).unwrap();
+        users.write().add(doc!{"name" => "Doe"}).unwrap();
+        assert_eq!(before.count(), 1);
+        assert_eq!(before.get(id).unwrap().get("name").unwrap().to_string(), "John");
+        assert_eq!(users.snapshot().get(id).unwrap().get("name").unwrap().to_string(), "Jane");
+
+        // the old version is freed with its last reader
+        let old = Arc::downgrade(&before);
+        drop(before);
+        assert!(old.upgrade().is_none());
+
+        // the readers don't wait for a writer and see its writes once it is dropped
+        let mut writer = users.write();
+        writer.add(doc!{"name" => "Smith"}).unwrap();
+        assert_eq!(writer.count(), 3);
+        assert_eq!(users.snapshot().count(), 2);
+        drop(writer);
+        assert_eq!(users.snapshot().count(), 3);
+    }
+
+    #[test]
+    fn panic_with_the_writer() {
+        let users = Arc::new(VersionedCollection::new(Collection::new("users".to_string())));
+        let writer = users.clone();
+        assert!(thread::spawn(move || {
+            let mut writer = writer.write();
+            writer.add(doc!{"name" => "Doe"}).unwrap();
+            panic!("the request failed");
+        }).join().is_err());
+        // the half done write is thrown away
+        assert_eq!(users.snapshot().count(), 0);
+        users.write().add(doc!{"name" => "John"}).unwrap();
+        assert_eq!(users.snapshot().count(), 1);
+    }
+
+    #[test]
+    fn scans_never_see_half_of_an_update() {
+        let users = Arc::new(VersionedCollection::new(Collection::new("users".to_string())));
+        for _ in 0..1000 {
+            users.write().add(doc!{"version" => 0}).unwrap();
+        }
+        let writer = {
+            let users = users.clone();
+            thread::spawn(move || {
+                let all = Filter::And(Vec::new());
+                let update = Update::from_json(r#"{"$inc": {"version": 1}}"#).unwrap();
+                for _ in 0..50 {
+                    users.write().update_many(&all, &update, false).unwrap();
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4).map(|_| {
+            let users = users.clone();
+            thread::spawn(move || {
+                let all = Filter::And(Vec::new());
+                let mut last = 0;
+                while last < 50 {
+                    let snapshot = users.snapshot();
+                    let versions: Vec<i32> = snapshot.query(&all).iter().map(|x| x.get("version").unwrap().to_number()).collect();
+                    assert_eq!(versions.len(), 1000);
+                    assert!(versions.iter().all(|version| *version == versions[0]));
+                    assert!(versions[0] >= last);
+                    last = versions[0];
+                }
+            })
+        }).collect();
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,242 @@
+// The path module resolves dot paths like "address.city" inside the documents,
+// and projects the documents to a subset of their fields
+//
+// A path goes through the arrays: "items.sku" is the sku of every document in items,
+// and a number selects one element: "tags.0" is the first tag
+// A key containing dots is still found by its full name
+
+use super::collection::Document;
+use super::data_type::DataType;
+
+const ID: &str = "ID";
+
+fn walk<'a>(value: &'a DataType, segments: &[&str], out: &mut Vec<&'a DataType>) {
+    let (segment, rest) = match segments.split_first() {
+        Some(split) => split,
+        None => return out.push(value),
+    };
+    match value {
+        DataType::Document(document) => {
+            if let Some(value) = document.get(*segment) {
+                walk(value, rest, out);
+            }
+        }
+        DataType::Array(items) => match segment.parse::<usize>() {
+            Ok(position) => {
+                if let Some(value) = items.get(position) {
+                    walk(value, rest, out);
+                }
+            }
+            Err(_) => items.iter().for_each(|item| walk(item, segments, out)),
+        },
+        _ => {}
+    }
+}
+
+// Values found at `path` in the document, empty if there is none
+pub fn values<'a>(document: &'a Document, path: &str) -> Vec<&'a DataType> {
+    if let Some(value) = document.get(path) {
+        return vec![value];
+    }
+    let segments: Vec<&str> = path.split('.').collect();
+    let mut out = Vec::new();
+    if let Some(value) = document.get(segments[0]) {
+        walk(value, &segments[1..], &mut out);
+    }
+    out
+}
+
+// Set the value at `path`, creating the documents missing in the way
+// returns false if the path goes through a value that is not a document
+pub fn set(document: &mut Document, path: &str, value: DataType) -> bool {
+    let (first, rest) = match path.split_once('.') {
+        Some(split) if !document.contains_key(path) => split,
+        _ => {
+            document.insert(path.to_string(), value);
+            return true;
+        }
+    };
+    match document.entry(first.to_string()).or_insert_with(|| DataType::Document(Document::new())) {
+        DataType::Document(inner) => set(inner, rest, value),
+        _ => false,
+    }
+}
+
+// Which fields of the documents are returned
+#[derive(Debug, PartialEq)]
+pub enum Projection {
+    // only these paths, and the ID unless `id` is false
+    Include { paths: Vec<String>, id: bool },
+    // everything but these paths
+    Exclude(Vec<String>),
+}
+
+// The part of `value` at the path, arrays keep one element per document they contain
+// so the parts of several paths can be merged element by element
+fn pick(value: &DataType, segments: &[&str]) -> Option<DataType> {
+    if segments.is_empty() {
+        return Some(value.clone());
+    }
+    match value {
+        DataType::Document(document) => pick_in(document, segments).map(DataType::Document),
+        DataType::Array(items) => Some(DataType::Array(items.iter()
+            .filter(|item| matches!(item, DataType::Document(_)))
+            .map(|item| pick(item, segments).unwrap_or(DataType::Document(Document::new())))
+            .collect())),
+        _ => None,
+    }
+}
+
+fn pick_in(document: &Document, segments: &[&str]) -> Option<Document> {
+    let (segment, rest) = segments.split_first()?;
+    let part = pick(document.get(*segment)?, rest)?;
+    Some(Document::from([(segment.to_string(), part)]))
+}
+
+fn merge_in(target: &mut Document, part: Document) {
+    for (key, value) in part {
+        match target.get_mut(&key) {
+            Some(existing) => merge(existing, value),
+            None => { target.insert(key, value); }
+        }
+    }
+}
+
+fn merge(target: &mut DataType, part: DataType) {
+    match (target, part) {
+        (DataType::Document(target), DataType::Document(part)) => merge_in(target, part),
+        (DataType::Array(target), DataType::Array(part)) => {
+            for (existing, value) in target.iter_mut().zip(part) {
+                merge(existing, value);
+            }
+        }
+        (target, part) => *target = part,
+    }
+}
+
+fn remove(document: &mut Document, segments: &[&str]) {
+    match segments {
+        [] => {}
+        [key] => { document.remove(*key); }
+        [key, rest @ ..] => match document.get_mut(*key) {
+            Some(DataType::Document(inner)) => remove(inner, rest),
+            Some(DataType::Array(items)) => {
+                for item in items.iter_mut() {
+                    if let DataType::Document(inner) = item {
+                        remove(inner, rest);
+                    }
+                }
+            }
+            _ => {}
+        },
+    }
+}
+
+impl Projection {
+    // Parse a list of paths separated by commas, the paths starting with - are excluded
+    // "name,address.city" or "-password,-address.geo", only the ID can be excluded
+    // from a list of included paths
+    pub fn parse(fields: &str) -> Result<Projection, String> {
+        let paths: Vec<&str> = fields.split(',').map(str::trim).filter(|path| !path.is_empty()).collect();
+        if paths.is_empty() {
+            return Err("fields can't be empty".to_string());
+        }
+        let excluded: Vec<String> = paths.iter().filter_map(|path| path.strip_prefix('-')).map(String::from).collect();
+        let included: Vec<String> = paths.iter().filter(|path| !path.starts_with('-')).map(|path| path.to_string()).collect();
+        if included.is_empty() {
+            return Ok(Projection::Exclude(excluded));
+        }
+        match excluded.as_slice() {
+            [] => Ok(Projection::Include { paths: included, id: true }),
+            [id] if id == ID => Ok(Projection::Include { paths: included, id: false }),
+            _ => Err("fields can't mix included and excluded paths".to_string()),
+        }
+    }
+
+    pub fn apply(&self, document: &Document) -> Document {
+        match self {
+            Projection::Include { paths, id } => {
+                let mut result = Document::new();
+                for path in paths {
+                    let segments: Vec<&str> = path.split('.').collect();
+                    // a key containing dots is taken by its full name
+                    let part = match document.get(path.as_str()) {
+                        Some(value) => Some(Document::from([(path.clone(), value.clone())])),
+                        None => pick_in(document, &segments),
+                    };
+                    if let Some(part) = part {
+                        merge_in(&mut result, part);
+                    }
+                }
+                match document.get(ID) {
+                    Some(value) if *id => { result.insert(ID.to_string(), value.clone()); }
+                    _ => {}
+                }
+                result
+            }
+            Projection::Exclude(paths) => {
+                let mut result = document.clone();
+                for path in paths {
+                    if result.remove(path.as_str()).is_none() {
+                        let segments: Vec<&str> = path.split('.').collect();
+                        remove(&mut result, &segments);
+                    }
+                }
+                result
+            }
+        }
+    }
+}
+
+
+//TEST
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use crate::memodb::collection::{Document, DocumentJson};
+    use super::{values, Projection};
+
+    fn document() -> Document {
+        Document::from_json(r#"{
+            "name": "John",
+            "tags": ["rust", "db"],
+            "address": {"city": "Madrid", "geo": {"lat": 40.4, "lng": -3.7}},
+            "items": [{"sku": "a", "qty": 1}, {"sku": "b", "qty": 2}, 3]
+        }"#)
+    }
+
+    fn json(document: &Document) -> Value {
+        serde_json::from_str(&document.to_json()).unwrap()
+    }
+
+    #[test]
+    fn resolve_paths() {
+        let document = document();
+        let texts = |path: &str| -> Vec<String> { values(&document, path).iter().map(|x| x.to_string()).collect() };
+        assert_eq!(texts("address.city"), vec!["Madrid"]);
+        assert_eq!(texts("items.sku"), vec!["a", "b"]);
+        assert_eq!(texts("tags.1"), vec!["db"]);
+        assert_eq!(texts("items.1.qty"), vec!["2"]);
+        assert!(values(&document, "address.zip").is_empty());
+        assert!(values(&document, "name.first").is_empty());
+    }
+
+    #[test]
+    fn project_documents() {
+        let document = document();
+        let projection = Projection::parse("name,address.geo.lat,items.sku,-ID").unwrap();
+        assert_eq!(json(&projection.apply(&document)), json!({
+            "name": "John",
+            "address": {"geo": {"lat": 40.4}},
+            "items": [{"sku": "a"}, {"sku": "b"}]
+        }));
+        let projection = Projection::parse("-tags,-address.geo,-items.qty").unwrap();
+        assert_eq!(json(&projection.apply(&document)), json!({
+            "name": "John",
+            "address": {"city": "Madrid"},
+            "items": [{"sku": "a"}, {"sku": "b"}, 3]
+        }));
+        assert!(Projection::parse("name,-tags").is_err());
+        assert!(Projection::parse(" , ").is_err());
+    }
+}
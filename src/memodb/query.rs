@@ -0,0 +1,269 @@
+// The query module will parse and evaluate the filters used to find documents
+// The filters are JSON documents with the same syntax as MongoDB:
+//
+//   {"name": "John"}                          equality
+//   {"age": {"$gte": 18, "$lt": 65}}          comparison, all the operators of a field must match
+//   {"$or": [{"age": 30}, {"name": "Jane"}]}  logical operators
+//
+// Supported operators: $eq, $ne, $gt, $gte, $lt, $lte, $in, $nin, $exists, $regex (with $options),
+// $not on a field, and $and, $or, $not on filters
+// Fields can be dot paths into nested documents, like "address.city", and a condition on an
+// array matches when any of its elements match, so {"tags": "rust"} finds the documents tagged rust
+
+use std::cmp::Ordering;
+use regex::{Regex, RegexBuilder};
+use serde_json::{Map, Value};
+use super::collection::Document;
+use super::data_type::DataType;
+use super::path;
+
+#[derive(Debug)]
+pub enum Condition {
+    Eq(DataType),
+    Ne(DataType),
+    Gt(DataType),
+    Gte(DataType),
+    Lt(DataType),
+    Lte(DataType),
+    In(Vec<DataType>),
+    Nin(Vec<DataType>),
+    Exists(bool),
+    Regex(Regex),
+}
+
+#[derive(Debug)]
+pub enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    Field(String, Condition),
+}
+
+fn compare_is(value: Option<&DataType>, other: &DataType, accept: fn(Ordering) -> bool) -> bool {
+    match value.and_then(|value| value.compare(other)) {
+        Some(ordering) => accept(ordering),
+        None => false,
+    }
+}
+
+impl Condition {
+    // `value` is the value of the field in the document, None if the document doesn't have it
+    pub fn matches(&self, value: Option<&DataType>) -> bool {
+        match self {
+            Condition::Eq(other) => value == Some(other),
+            Condition::Ne(other) => value != Some(other),
+            Condition::Gt(other) => compare_is(value, other, Ordering::is_gt),
+            Condition::Gte(other) => compare_is(value, other, Ordering::is_ge),
+            Condition::Lt(other) => compare_is(value, other, Ordering::is_lt),
+            Condition::Lte(other) => compare_is(value, other, Ordering::is_le),
+            Condition::In(list) => value.is_some_and(|value| list.contains(value)),
+            Condition::Nin(list) => !value.is_some_and(|value| list.contains(value)),
+            Condition::Exists(exists) => value.is_some() == *exists,
+            Condition::Regex(regex) => match value {
+                Some(DataType::Text(text)) => regex.is_match(text),
+                _ => false,
+            },
+        }
+    }
+
+    // `values` are the values of the field in the document, more than one when its path
+    // goes through arrays; an array matches when itself or any of its elements match,
+    // and $ne and $nin match when none of them is equal
+    pub fn matches_values(&self, values: &[&DataType]) -> bool {
+        let any = |accept: &dyn Fn(&DataType) -> bool| values.iter().any(|value| {
+            accept(value) || matches!(value, DataType::Array(items) if items.iter().any(accept))
+        });
+        match self {
+            Condition::Ne(other) => !any(&|value| value == other),
+            Condition::Nin(list) => !any(&|value| list.contains(value)),
+            Condition::Exists(exists) => values.is_empty() != *exists,
+            _ if values.is_empty() => self.matches(None),
+            _ => any(&|value| self.matches(Some(value))),
+        }
+    }
+
+    fn parse(operator: &str, value: &Value, options: Option<&Value>) -> Result<Condition, String> {
+        let list = |value: &Value| match value {
+            Value::Array(array) => Ok(array.iter().map(DataType::from_value).collect()),
+            _ => Err(format!("{} expects an array", operator)),
+        };
+        let condition = match operator {
+            "$eq" => Condition::Eq(DataType::from_value(value)),
+            "$ne" => Condition::Ne(DataType::from_value(value)),
+            "$gt" => Condition::Gt(DataType::from_value(value)),
+            "$gte" => Condition::Gte(DataType::from_value(value)),
+            "$lt" => Condition::Lt(DataType::from_value(value)),
+            "$lte" => Condition::Lte(DataType::from_value(value)),
+            "$in" => Condition::In(list(value)?),
+            "$nin" => Condition::Nin(list(value)?),
+            "$exists" => match value {
+                Value::Bool(exists) => Condition::Exists(*exists),
+                _ => return Err("$exists expects a boolean".to_string()),
+            },
+            "$regex" => {
+                let pattern = value.as_str().ok_or("$regex expects a string")?;
+                let options = match options {
+                    Some(options) => options.as_str().ok_or("$options expects a string")?,
+                    None => "",
+                };
+                let mut builder = RegexBuilder::new(pattern);
+                for option in options.chars() {
+                    match option {
+                        'i' => builder.case_insensitive(true),
+                        'm' => builder.multi_line(true),
+                        's' => builder.dot_matches_new_line(true),
+                        'x' => builder.ignore_whitespace(true),
+                        _ => return Err(format!("Unknown $regex option {}", option)),
+                    };
+                }
+                Condition::Regex(builder.build().map_err(|e| e.to_string())?)
+            }
+            _ => return Err(format!("Unknown operator {}", operator)),
+        };
+        Ok(condition)
+    }
+}
+
+impl Filter {
+    pub fn matches(&self, document: &Document) -> bool {
+        match self {
+            Filter::And(filters) => filters.iter().all(|filter| filter.matches(document)),
+            Filter::Or(filters) => filters.iter().any(|filter| filter.matches(document)),
+            Filter::Not(filter) => !filter.matches(document),
+            Filter::Field(field, condition) => condition.matches_values(&path::values(document, field)),
+        }
+    }
+
+    // Parse a filter from its JSON representation
+    pub fn from_json(json: &str) -> Result<Filter, String> {
+        let value: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        Filter::from_value(&value)
+    }
+
+    pub fn from_value(value: &Value) -> Result<Filter, String> {
+        match value {
+            Value::Object(object) => Filter::parse_document(object),
+            _ => Err("A filter must be a JSON object".to_string()),
+        }
+    }
+
+    fn parse_list(operator: &str, value: &Value) -> Result<Vec<Filter>, String> {
+        match value {
+            Value::Array(filters) if !filters.is_empty() => filters.iter().map(Filter::from_value).collect(),
+            _ => Err(format!("{} expects a non empty array of filters", operator)),
+        }
+    }
+
+    fn parse_document(object: &Map<String, Value>) -> Result<Filter, String> {
+        let mut filters = Vec::new();
+        for (key, value) in object {
+            let filter = match key.as_str() {
+                "$and" => Filter::And(Filter::parse_list(key, value)?),
+                "$or" => Filter::Or(Filter::parse_list(key, value)?),
+                "$not" => Filter::Not(Box::new(Filter::from_value(value)?)),
+                _ if key.starts_with('$') => return Err(format!("Unknown operator {}", key)),
+                _ => Filter::parse_field(key, value)?,
+            };
+            filters.push(filter);
+        }
+        Ok(Filter::And(filters).simplify())
+    }
+
+    pub(crate) fn parse_field(field: &str, value: &Value) -> Result<Filter, String> {
+        let operators = match value {
+            Value::Object(object) if object.keys().any(|key| key.starts_with('$')) => object,
+            // a plain value is an equality
+            _ => return Ok(Filter::Field(field.to_string(), Condition::Eq(DataType::from_value(value)))),
+        };
+        let mut filters = Vec::new();
+        for (operator, value) in operators {
+            let filter = match operator.as_str() {
+                "$options" if operators.contains_key("$regex") => continue,
+                "$not" => match value {
+                    Value::Object(_) => Filter::Not(Box::new(Filter::parse_field(field, value)?)),
+                    _ => return Err("$not expects an operator document".to_string()),
+                },
+                _ => Filter::Field(field.to_string(), Condition::parse(operator, value, operators.get("$options"))?),
+            };
+            filters.push(filter);
+        }
+        Ok(Filter::And(filters).simplify())
+    }
+
+    fn simplify(self) -> Filter {
+        match self {
+            Filter::And(mut filters) if filters.len() == 1 => filters.pop().unwrap(),
+            filter => filter,
+        }
+    }
+}
+
+
+//TEST
+#[cfg(test)]
+mod tests {
+    use crate::doc;
+    use super::Filter;
+
+    fn matches(filter: &str, document: &crate::memodb::collection::Document) -> bool {
+        Filter::from_json(filter).unwrap().matches(document)
+    }
+
+    #[test]
+    fn comparison_operators() {
+        let john = doc!{"name" => "John", "age" => 30, "birthDate" => "1994-02-01"};
+        assert!(matches(r#"{"name": "John"}"#, &john));
+        assert!(matches(r#"{"age": {"$gt": 18, "$lte": 30}}"#, &john));
+        assert!(!matches(r#"{"age": {"$lt": 30}}"#, &john));
+        assert!(matches(r#"{"birthDate": {"$gte": "1990-01-01", "$lt": "2000-01-01"}}"#, &john));
+        assert!(matches(r#"{"age": {"$ne": 25}, "name": {"$ne": "Jane"}}"#, &john));
+        // values of a different type are never in range
+        assert!(!matches(r#"{"age": {"$gt": "18"}}"#, &john));
+    }
+
+    #[test]
+    fn set_and_existence_operators() {
+        let john = doc!{"name" => "John", "age" => 30};
+        assert!(matches(r#"{"age": {"$in": [25, 30]}}"#, &john));
+        assert!(!matches(r#"{"age": {"$nin": [25, 30]}}"#, &john));
+        assert!(matches(r#"{"email": {"$nin": ["a@b.c"]}}"#, &john));
+        assert!(matches(r#"{"email": {"$exists": false}, "age": {"$exists": true}}"#, &john));
+        assert!(matches(r#"{"name": {"$regex": "^jo", "$options": "i"}}"#, &john));
+        assert!(!matches(r#"{"name": {"$regex": "^jo"}}"#, &john));
+        assert!(!matches(r#"{"age": {"$regex": "30"}}"#, &john));
+    }
+
+    #[test]
+    fn logical_operators() {
+        let john = doc!{"name" => "John", "age" => 30};
+        assert!(matches(r#"{"$or": [{"age": 25}, {"name": "John"}]}"#, &john));
+        assert!(!matches(r#"{"$and": [{"age": 30}, {"name": "Jane"}]}"#, &john));
+        assert!(matches(r#"{"$not": {"name": "Jane"}}"#, &john));
+        assert!(matches(r#"{"age": {"$not": {"$gt": 40}}}"#, &john));
+        assert!(!matches(r#"{"age": {"$not": {"$gt": 20}}}"#, &john));
+    }
+
+    #[test]
+    fn nested_paths_and_arrays() {
+        let john = crate::memodb::collection::DocumentJson::from_json(
+            r#"{"tags": ["rust", "db"], "address": {"city": "Madrid"}, "orders": [{"total": 10}, {"total": 40}]}"#
+        );
+        assert!(matches(r#"{"address.city": "Madrid"}"#, &john));
+        assert!(matches(r#"{"tags": "rust"}"#, &john));
+        assert!(matches(r#"{"tags": ["rust", "db"]}"#, &john));
+        assert!(!matches(r#"{"tags": {"$ne": "rust"}}"#, &john));
+        assert!(matches(r#"{"tags": {"$nin": ["go"]}}"#, &john));
+        assert!(matches(r#"{"orders.total": {"$gt": 30}}"#, &john));
+        assert!(matches(r#"{"orders.0.total": 10}"#, &john));
+        assert!(matches(r#"{"address.zip": {"$exists": false}, "orders.total": {"$exists": true}}"#, &john));
+    }
+
+    #[test]
+    fn invalid_filters() {
+        assert!(Filter::from_json("[]").is_err());
+        assert!(Filter::from_json(r#"{"age": {"$between": [1, 2]}}"#).is_err());
+        assert!(Filter::from_json(r#"{"$or": []}"#).is_err());
+        assert!(Filter::from_json(r#"{"age": {"$in": 3}}"#).is_err());
+        assert!(Filter::from_json(r#"{"name": {"$regex": "("}}"#).is_err());
+    }
+}
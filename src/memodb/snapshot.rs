@@ -0,0 +1,153 @@
+// The snapshot module will persist the MEMOdb collections on disk
+// A snapshot is a single JSON file with every collection and its documents
+//
+// Snapshots are written to a temporary file that is renamed over the previous one,
+// so a crash in the middle of a save never corrupts the last good snapshot
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use super::collection::{Collection, Document};
+use super::index::IndexSpec;
+
+#[derive(Serialize, Deserialize)]
+struct CollectionSnapshot {
+    name: String,
+    documents: Vec<Document>,
+    // the indexes are rebuilt when the snapshot is loaded
+    #[serde(default)]
+    indexes: Vec<IndexSpec>,
+    // sequence numbers of the documents, kept so the pagination cursors survive a restart
+    #[serde(default)]
+    seqs: Vec<u64>,
+    #[serde(default)]
+    next_seq: u64,
+    // revisions of the documents, kept so the ETags survive a restart
+    #[serde(default)]
+    revs: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: String,
+    // sequence number of the last write-ahead log record included in the snapshot
+    #[serde(default)]
+    lsn: u64,
+    collections: Vec<CollectionSnapshot>,
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+// Write all the collections to `path`
+pub fn save(path: &Path, version: &str, lsn: u64, collections: &[&Collection]) -> io::Result<()> {
+    let snapshot = Snapshot {
+        version: version.to_string(),
+        lsn,
+        collections: collections
+            .iter()
+            .map(|collection| CollectionSnapshot {
+                name: collection.name.clone(),
+                documents: collection.entries().map(|(_, _, document)| document.clone()).collect(),
+                indexes: collection.get_indexes().into_iter().cloned().collect(),
+                seqs: collection.entries().map(|(seq, _, _)| seq).collect(),
+                next_seq: collection.next_seq,
+                revs: collection.entries().map(|(_, rev, _)| rev).collect(),
+            })
+            .collect(),
+    };
+    let tmp = temp_path(path);
+    let file = File::create(&tmp)?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, &snapshot)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+    fs::rename(&tmp, path)?;
+    // make the rename itself durable, not every platform allows to open a directory
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
+// Read the collections stored in `path` and the lsn of the snapshot
+// returns an ErrorKind::NotFound error if there is no snapshot yet
+pub fn load(path: &Path) -> io::Result<(Vec<Collection>, u64)> {
+    let file = File::open(path)?;
+    let snapshot: Snapshot = serde_json::from_reader(BufReader::new(file))?;
+    let collections = snapshot
+        .collections
+        .into_iter()
+        .map(|stored| {
+            let mut collection = Collection::new(stored.name);
+            let count = stored.documents.len();
+            // snapshots written before the sequence numbers or the revisions were kept don't have them
+            let seqs = if stored.seqs.len() == count { stored.seqs } else { (0..count as u64).collect() };
+            let revs = if stored.revs.len() == count { stored.revs } else { vec![1; count] };
+            for ((document, seq), rev) in stored.documents.into_iter().zip(seqs).zip(revs) {
+                collection.insert_at(document, seq, rev);
+            }
+            collection.next_seq = stored.next_seq.max(collection.next_seq);
+            for index in stored.indexes {
+                collection.build_index(index);
+            }
+            collection
+        })
+        .collect();
+    Ok((collections, snapshot.lsn))
+}
+
+
+//TEST
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use crate::doc;
+    use crate::memodb::collection::Collection;
+    use crate::memodb::index::IndexSpec;
+    use super::{load, save, temp_path};
+
+    #[test]
+    fn save_and_load() {
+        let path = env::temp_dir().join(format!("memodb-snapshot-{}.json", uuid::Uuid::new_v4()));
+        let mut users = Collection::new("users".to_string());
+        let id = users.add(doc!{"name" => "John", "age" => 30}).unwrap();
+        users.create_index(IndexSpec::new("by_age", &["-age"], false)).unwrap();
+        let removed = users.add(doc!{"name" => "Doe"}).unwrap();
+        users.rm(removed).unwrap();
+        let jane = users.add(doc!{"name" => "Jane", "age" => 25}).unwrap();
+        users.update_document(jane, doc!{"age" => 26}).unwrap();
+        let posts = Collection::new("posts".to_string());
+        save(&path, "test", 7, &[&users, &posts]).unwrap();
+        assert!(!temp_path(&path).exists());
+
+        let (mut collections, lsn) = load(&path).unwrap();
+        assert_eq!(lsn, 7);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(collections.len(), 2);
+        assert_eq!(collections[1].name, "posts");
+        let users = &mut collections[0];
+        assert_eq!(users.count(), 2);
+        assert_eq!(users.get(id).unwrap().get("name").unwrap().to_string(), "John");
+        assert_eq!(users.get_indexes()[0], &IndexSpec::new("by_age", &["-age"], false));
+        // the sequence numbers of the documents are kept
+        let entries: Vec<(u64, u64)> = users.entries().map(|(seq, rev, _)| (seq, rev)).collect();
+        assert_eq!(entries, vec![(0, 1), (2, 2)]);
+        assert_eq!(users.next_seq, 3);
+    }
+
+    #[test]
+    fn load_missing_snapshot() {
+        let path = env::temp_dir().join(format!("memodb-missing-{}.json", uuid::Uuid::new_v4()));
+        let err = load(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,89 @@
+// The sort module orders the documents returned by the queries
+// A sort is a list of fields, "age,-name" orders by age and then by name descending
+//
+// Values of different types are ordered by type, the same order of the indexes:
+// missing < null < numbers < text < documents < arrays < ids < booleans
+// and a descending field reverses it, so missing values go last
+// When a path goes through an array of documents and finds several values,
+// they are compared as an array
+// The sort is stable, documents with the same values keep their insertion order
+
+use std::fmt;
+use super::collection::Document;
+use super::data_type::DataType;
+use super::index::{IndexField, IndexKey, KeyPart};
+use super::path;
+
+#[derive(Debug, PartialEq)]
+pub struct Sort {
+    pub fields: Vec<IndexField>,
+}
+
+impl Sort {
+    pub fn parse(sort: &str) -> Result<Sort, String> {
+        let fields: Vec<IndexField> = sort.split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty() && *field != "-")
+            .map(IndexField::parse)
+            .collect();
+        if fields.is_empty() {
+            return Err("sort can't be empty".to_string());
+        }
+        Ok(Sort { fields })
+    }
+
+    pub fn key(&self, document: &Document) -> IndexKey {
+        IndexKey(self.fields.iter().map(|field| {
+            let mut values = path::values(document, &field.name);
+            let value = match values.len() {
+                0 => None,
+                1 => values.pop().cloned(),
+                _ => Some(DataType::Array(values.into_iter().cloned().collect())),
+            };
+            KeyPart { value, descending: field.descending }
+        }).collect())
+    }
+
+    pub fn apply(&self, documents: &mut [&Document]) {
+        documents.sort_by_cached_key(|document| self.key(document));
+    }
+}
+
+impl fmt::Display for Sort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let fields: Vec<String> = self.fields.iter().map(|field| field.to_string()).collect();
+        write!(f, "{}", fields.join(","))
+    }
+}
+
+
+//TEST
+#[cfg(test)]
+mod tests {
+    use crate::doc;
+    use crate::memodb::collection::Document;
+    use super::Sort;
+
+    #[test]
+    fn sort_mixed_types() {
+        let documents = [
+            doc!{"n" => 1, "v" => "text"},
+            doc!{"n" => 2, "v" => 2.5},
+            doc!{"n" => 3},
+            doc!{"n" => 4, "v" => true},
+            doc!{"n" => 5, "v" => 2},
+            doc!{"n" => 6, "v" => DataType::Null},
+            doc!{"n" => 7, "v" => 2.0},
+        ];
+        let order = |sort: &str| -> Vec<i32> {
+            let mut sorted: Vec<&Document> = documents.iter().collect();
+            Sort::parse(sort).unwrap().apply(&mut sorted);
+            sorted.iter().map(|x| x.get("n").unwrap().to_number()).collect()
+        };
+        // 2 and 2.0 are equal and keep their order
+        assert_eq!(order("v"), vec![3, 6, 5, 7, 2, 1, 4]);
+        assert_eq!(order("-v"), vec![4, 1, 2, 5, 7, 6, 3]);
+        assert_eq!(order("-v,-n"), vec![4, 1, 2, 7, 5, 6, 3]);
+        assert!(Sort::parse(" ,").is_err());
+    }
+}
@@ -0,0 +1,345 @@
+// The transaction module writes to several collections all or nothing
+// The writes are applied as they come and the undo of each one is kept,
+// when the transaction commits they are logged as a single batch of the write-ahead log
+// An error, or a failure writing the log, undoes all of them in reverse order
+//
+// A transaction holds the writer of every collection until it ends, so its reads see the
+// documents as they were when it began plus its own writes, and no one sees its writes
+// before it commits
+// The collections are published one after the other when it commits, a reader of several
+// collections can see the writes in one of them and not yet in another
+//
+// Over HTTP a transaction is a list of the operations of the bulk writes with their collection,
+// and move, which takes a document out of a collection into another one keeping its ID:
+//
+//   [{"collection": "orders", "move": {"id": "<uuid>", "to": "archived_orders"}},
+//    {"collection": "stats", "update": {"id": "<uuid>", "update": {"$inc": {"archived": 1}}}}]
+
+use std::sync::Arc;
+use serde_json::{Map, Value};
+use uuid::Uuid;
+use super::bulk::{Change, Operation, Outcome};
+use super::collection::{Collection, Document, Removed};
+use super::error::DbError;
+use super::mvcc::Writer;
+use super::update::Update;
+use super::wal::{Record, Wal};
+use super::MEMOdb;
+
+enum Undo {
+    Added { collection: String, id: Uuid },
+    Replaced { collection: String, id: Uuid, saved: (Document, u64) },
+    Removed { collection: String, removed: Removed },
+}
+
+pub struct Transaction<'a> {
+    collections: Vec<Writer<'a>>,
+    // the logs of the collections written, their writes are logged together when the transaction commits
+    wals: Vec<(usize, Arc<Wal>)>,
+    records: Vec<Record>,
+    undo: Vec<Undo>,
+}
+
+impl Transaction<'_> {
+    // The collection to write, only the collections written are copied from the version of their readers
+    fn collection(&mut self, name: &str) -> Result<&mut Collection, DbError> {
+        let position = self.collections.iter().position(|x| x.name == name).ok_or(DbError::CollectionNotFound)?;
+        let collection = &mut *self.collections[position];
+        if let Some(wal) = collection.wal.take() {
+            self.wals.push((position, wal));
+        }
+        Ok(collection)
+    }
+
+    pub fn get(&self, collection: &str, id: Uuid) -> Result<&Document, DbError> {
+        let collection = self.collections.iter().find(|x| x.name == collection).ok_or(DbError::CollectionNotFound)?;
+        collection.get(id).ok_or(DbError::DocumentNotFound)
+    }
+
+    pub fn insert(&mut self, collection: &str, document: Document) -> Result<Uuid, DbError> {
+        let target = self.collection(collection)?;
+        let id = target.add(document)?;
+        let document = target.get(id).unwrap().clone();
+        self.records.push(Record::Add { collection: collection.to_string(), document });
+        self.undo.push(Undo::Added { collection: collection.to_string(), id });
+        Ok(id)
+    }
+
+    // Apply the update operators to the document `id`
+    pub fn update(&mut self, collection: &str, id: Uuid, update: &Update) -> Result<(), DbError> {
+        let saved = self.saved(collection, id)?;
+        self.collection(collection)?.patch_document(id, update)?;
+        self.replaced(collection, id, saved);
+        Ok(())
+    }
+
+    // Merge the fields of `document` into the document `id`
+    pub fn merge(&mut self, collection: &str, id: Uuid, document: Document) -> Result<(), DbError> {
+        let saved = self.saved(collection, id)?;
+        self.collection(collection)?.update_document(id, document)?;
+        self.replaced(collection, id, saved);
+        Ok(())
+    }
+
+    // Replace the document `id`, or add it when it doesn't exist and `upsert` is true
+    // returns whether it was added
+    pub fn replace(&mut self, collection: &str, id: Uuid, document: Document, upsert: bool) -> Result<bool, DbError> {
+        let saved = self.saved(collection, id).ok();
+        let (document, added) = self.collection(collection)?.replace_document(id, document, upsert)?;
+        let document = document.clone();
+        match saved {
+            Some(saved) => self.replaced(collection, id, saved),
+            None => {
+                self.records.push(Record::Add { collection: collection.to_string(), document });
+                self.undo.push(Undo::Added { collection: collection.to_string(), id });
+            }
+        }
+        Ok(added)
+    }
+
+    pub fn delete(&mut self, collection: &str, id: Uuid) -> Result<Document, DbError> {
+        let removed = self.collection(collection)?.take(id).ok_or(DbError::DocumentNotFound)?;
+        let document = removed.document.clone();
+        self.records.push(Record::Remove { collection: collection.to_string(), id });
+        self.undo.push(Undo::Removed { collection: collection.to_string(), removed });
+        Ok(document)
+    }
+
+    // Take the document `id` out of `from` into `to`, keeping its ID
+    pub fn move_to(&mut self, from: &str, id: Uuid, to: &str) -> Result<(), DbError> {
+        // fail before any write when the target collection is missing or has that ID
+        match self.get(to, id) {
+            Ok(_) => return Err(DbError::DocumentExists),
+            Err(DbError::CollectionNotFound) => return Err(DbError::CollectionNotFound),
+            Err(_) => {}
+        }
+        let document = self.delete(from, id)?;
+        self.insert(to, document)?;
+        Ok(())
+    }
+
+    // The document `id` and its revision, put back by the undo of a write
+    fn saved(&self, collection: &str, id: Uuid) -> Result<(Document, u64), DbError> {
+        let collection = self.collections.iter().find(|x| x.name == collection).ok_or(DbError::CollectionNotFound)?;
+        collection.saved(id).ok_or(DbError::DocumentNotFound)
+    }
+
+    // Log the new version of the document `id`, `saved` is put back by the undo
+    fn replaced(&mut self, collection: &str, id: Uuid, saved: (Document, u64)) {
+        let document = self.get(collection, id).unwrap().clone();
+        self.records.push(Record::Replace { collection: collection.to_string(), id, document });
+        self.undo.push(Undo::Replaced { collection: collection.to_string(), id, saved });
+    }
+
+    fn rollback(collections: &mut [Writer], undo: Vec<Undo>) {
+        let find = |collections: &mut [Writer], name: &str| collections.iter().position(|x| x.name == name).unwrap();
+        for undo in undo.into_iter().rev() {
+            match undo {
+                Undo::Added { collection, id } => {
+                    let position = find(collections, &collection);
+                    collections[position].take_back(id);
+                }
+                Undo::Replaced { collection, id, saved } => {
+                    let position = find(collections, &collection);
+                    collections[position].revert(id, saved);
+                }
+                Undo::Removed { collection, removed } => {
+                    let position = find(collections, &collection);
+                    collections[position].put_back(removed);
+                }
+            }
+        }
+    }
+
+    // Run one of the operations of an HTTP transaction
+    pub fn run(&mut self, step: Step) -> Result<Outcome, DbError> {
+        let (collection, operation) = match step {
+            Step::Move { from, id, to } => return self.move_to(&from, id, &to).map(|_| Outcome::Moved(id)),
+            Step::Write { collection, operation } => (collection, operation),
+        };
+        match operation {
+            Operation::Insert(document) => self.insert(&collection, document).map(Outcome::Inserted),
+            Operation::Update { id, change: Change::Operators(update) } => {
+                self.update(&collection, id, &update).map(|_| Outcome::Updated(id))
+            }
+            Operation::Update { id, change: Change::Merge(document) } => {
+                self.merge(&collection, id, document).map(|_| Outcome::Updated(id))
+            }
+            Operation::Replace { id, document, upsert } => {
+                self.replace(&collection, id, document, upsert).map(|added| Outcome::Replaced { id, added })
+            }
+            Operation::Delete { id } => self.delete(&collection, id).map(|_| Outcome::Deleted(id)),
+        }
+    }
+}
+
+impl Drop for Transaction<'_> {
+    // also when the writes panic, the collections don't keep writing without their log
+    fn drop(&mut self) {
+        for (position, wal) in self.wals.drain(..) {
+            self.collections[position].wal = Some(wal);
+        }
+        let undo = std::mem::take(&mut self.undo);
+        Transaction::rollback(&mut self.collections, undo);
+    }
+}
+
+// An operation of an HTTP transaction
+pub enum Step {
+    Write { collection: String, operation: Operation },
+    Move { from: String, id: Uuid, to: String },
+}
+
+impl Step {
+    // Parse a JSON array of operations, each one with its collection
+    pub fn parse_list(body: &str) -> Result<Vec<Step>, String> {
+        let steps = match serde_json::from_str(body) {
+            Ok(Value::Array(steps)) if !steps.is_empty() => steps,
+            Ok(_) => return Err("A transaction must be a non empty JSON array of operations".to_string()),
+            Err(e) => return Err(e.to_string()),
+        };
+        steps.iter().enumerate()
+            .map(|(index, step)| Step::parse(step).map_err(|e| format!("Operation {}: {}", index, e)))
+            .collect()
+    }
+
+    fn parse(value: &Value) -> Result<Step, String> {
+        let mut object: Map<String, Value> = value.as_object().cloned().ok_or("An operation must be a JSON object")?;
+        let collection = match object.remove("collection") {
+            Some(Value::String(collection)) => collection,
+            _ => return Err("The operation needs its collection".to_string()),
+        };
+        if let Some(arguments) = object.get("move") {
+            let id = arguments.get("id").and_then(Value::as_str).and_then(|id| Uuid::parse_str(id).ok());
+            let to = arguments.get("to").and_then(Value::as_str);
+            return match (object.len(), id, to) {
+                (1, Some(id), Some(to)) => Ok(Step::Move { from: collection, id, to: to.to_string() }),
+                _ => Err("move needs the id of the document and the collection it goes to".to_string()),
+            };
+        }
+        let operation = Operation::parse(&Value::Object(object))?;
+        Ok(Step::Write { collection, operation })
+    }
+}
+
+impl MEMOdb {
+    // Run `writes` in a transaction, committed when it returns Ok and undone when it returns an error
+    pub fn transaction<T>(&self, writes: impl FnOnce(&mut Transaction) -> Result<T, DbError>) -> Result<T, DbError> {
+        let list = self.collections.read().unwrap();
+        let mut transaction = Transaction {
+            collections: list.iter().map(|(_, collection)| collection.write()).collect(),
+            wals: Vec::new(),
+            records: Vec::new(),
+            undo: Vec::new(),
+        };
+        let mut result = writes(&mut transaction);
+        if result.is_ok() && !transaction.records.is_empty() {
+            let records = std::mem::take(&mut transaction.records);
+            if let Err(e) = self.log(Record::Batch { records }) {
+                result = Err(e);
+            }
+        }
+        if result.is_ok() {
+            transaction.undo.clear();
+        }
+        // dropping the transaction gives the logs back and undoes the writes not committed
+        drop(transaction);
+        result
+    }
+}
+
+
+//TEST
+#[cfg(test)]
+mod tests {
+    use crate::doc;
+    use crate::memodb::error::DbError;
+    use crate::memodb::index::IndexSpec;
+    use crate::memodb::update::Update;
+    use crate::memodb::wal::SyncPolicy;
+    use crate::memodb::MEMOdb;
+    use super::Step;
+
+    #[test]
+    fn commit_and_rollback() {
+        let dir = std::env::temp_dir();
+        let snapshot_path = dir.join(format!("memodb-{}.json", uuid::Uuid::new_v4()));
+        let wal_path = dir.join(format!("memodb-{}.wal", uuid::Uuid::new_v4()));
+        let open = || MEMOdb::open(&snapshot_path, &wal_path, SyncPolicy::Always).unwrap();
+        let db = open();
+        db.create_collection("orders".to_string()).unwrap();
+        db.create_collection("archived".to_string()).unwrap();
+        let orders = db.get_collection("orders".to_string()).unwrap();
+        let mut orders = orders.write();
+        orders.create_index(IndexSpec::new("number", &["number"], true)).unwrap();
+        let first = orders.add(doc!{"number" => 1}).unwrap();
+        let second = orders.add(doc!{"number" => 2}).unwrap();
+        let third = orders.add(doc!{"number" => 3}).unwrap();
+        drop(orders);
+
+        // the second write fails, the first one is undone
+        let result = db.transaction(|tx| {
+            tx.move_to("orders", second, "archived")?;
+            tx.update("orders", first, &Update::from_json(r#"{"$set": {"number": 3}}"#).unwrap())
+        });
+        assert!(matches!(result, Err(DbError::UniqueViolation { .. })));
+        let orders = db.get_collection("orders".to_string()).unwrap();
+        let orders = orders.snapshot();
+        let entries: Vec<(u64, u64, i32)> = orders.entries().map(|(seq, rev, x)| (seq, rev, x.get("number").unwrap().to_number())).collect();
+        assert_eq!(entries, vec![(0, 1, 1), (1, 1, 2), (2, 1, 3)]);
+        let archived = db.get_collection("archived".to_string()).unwrap().snapshot();
+        assert_eq!((archived.count(), archived.next_seq), (0, 0));
+        drop((orders, archived));
+
+        let steps = Step::parse_list(&format!(r#"[
+            {{"collection": "orders", "move": {{"id": "{second}", "to": "archived"}}}},
+            {{"collection": "orders", "delete": {{"id": "{third}"}}}},
+            {{"collection": "archived", "insert": {{"number": 4}}}}
+        ]"#)).unwrap();
+        db.transaction(|tx| steps.into_iter().try_for_each(|step| tx.run(step).map(|_| ()))).unwrap();
+        let seqs: Vec<u64> = db.get_collection("archived".to_string()).unwrap().snapshot().entries().map(|(seq, _, _)| seq).collect();
+        drop(db);
+
+        // the batch is replayed from the log
+        let db = open();
+        std::fs::remove_file(&wal_path).unwrap();
+        assert_eq!(db.get_collection("orders".to_string()).unwrap().snapshot().count(), 1);
+        let archived = db.get_collection("archived".to_string()).unwrap();
+        let archived = archived.snapshot();
+        assert_eq!(archived.count(), 2);
+        assert_eq!(archived.get(second).unwrap().get("ID").unwrap().to_id(), second);
+        assert_eq!(archived.entries().map(|(seq, _, _)| seq).collect::<Vec<u64>>(), seqs);
+        assert!(Step::parse_list(r#"[{"move": {}}]"#).is_err());
+        assert!(Step::parse_list("[]").is_err());
+    }
+
+    #[test]
+    fn panic_in_a_transaction() {
+        let dir = std::env::temp_dir();
+        let snapshot_path = dir.join(format!("memodb-{}.json", uuid::Uuid::new_v4()));
+        let wal_path = dir.join(format!("memodb-{}.wal", uuid::Uuid::new_v4()));
+        let open = || MEMOdb::open(&snapshot_path, &wal_path, SyncPolicy::Always).unwrap();
+        let db = open();
+        db.create_collection("orders".to_string()).unwrap();
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            db.transaction(|tx| {
+                tx.insert("orders", doc!{"number" => 1})?;
+                panic!("the request failed");
+                #[allow(unreachable_code)]
+                Ok(())
+            })
+        }));
+        assert!(panicked.is_err());
+        let orders = db.get_collection("orders".to_string()).unwrap();
+        assert_eq!(orders.snapshot().count(), 0);
+
+        // the writes after it are still logged
+        orders.write().add(doc!{"number" => 2}).unwrap();
+        drop((orders, db));
+        let db = open();
+        std::fs::remove_file(&wal_path).unwrap();
+        let orders = db.get_collection("orders".to_string()).unwrap().snapshot();
+        let numbers: Vec<i32> = orders.entries().map(|(_, _, x)| x.get("number").unwrap().to_number()).collect();
+        assert_eq!(numbers, vec![2]);
+    }
+}
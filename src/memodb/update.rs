@@ -0,0 +1,419 @@
+// The update module parses and applies the update operators of PATCH, with the MongoDB syntax:
+//
+//   {"$set": {"address.city": "Madrid"}, "$inc": {"visits": 1}, "$push": {"tags": "rust"}}
+//
+// Operators: $set, $unset, $inc, $mul, $min, $max, $rename, $push, $pull, $addToSet and $currentDate
+// Fields are dot paths, a number selects an element of an array: "items.0.qty"
+// The missing documents of a path are created, and the ID can't be changed
+// An update is applied to a copy of the document, so a failing operator changes nothing
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_json::{Map, Value};
+use super::collection::Document;
+use super::data_type::DataType;
+use super::query::Filter;
+
+const ID: &str = "ID";
+// name of the element of an array in the $pull conditions
+const ELEMENT: &str = "element";
+
+#[derive(Debug)]
+enum Pull {
+    // elements equal to the value
+    Value(DataType),
+    // elements matching the operators, {"$gte": 6}
+    Condition(Filter),
+    // documents matching the filter, {"score": {"$lt": 5}}
+    Document(Filter),
+}
+
+#[derive(Debug)]
+enum Operation {
+    Set(DataType),
+    Unset,
+    Inc(DataType),
+    Mul(DataType),
+    Min(DataType),
+    Max(DataType),
+    Rename(String),
+    Push(Vec<DataType>),
+    Pull(Pull),
+    AddToSet(Vec<DataType>),
+    // the current time as ISO 8601 text, or as milliseconds since the epoch for a timestamp
+    CurrentDate { timestamp: bool },
+}
+
+#[derive(Debug)]
+pub struct Update {
+    // operations in the order they are given, each one on its path
+    operations: Vec<(String, Operation)>,
+}
+
+fn number(operator: &str, value: &Value) -> Result<DataType, String> {
+    match value {
+        Value::Number(_) => Ok(DataType::from_value(value)),
+        _ => Err(format!("{} expects numbers", operator)),
+    }
+}
+
+// The values of $push and $addToSet, {"$each": [..]} adds several
+fn each(value: &Value) -> Vec<DataType> {
+    match value.as_object().and_then(|object| object.get("$each")) {
+        Some(Value::Array(values)) => values.iter().map(DataType::from_value).collect(),
+        _ => vec![DataType::from_value(value)],
+    }
+}
+
+impl Operation {
+    fn parse(operator: &str, value: &Value) -> Result<Operation, String> {
+        match operator {
+            "$set" => Ok(Operation::Set(DataType::from_value(value))),
+            "$unset" => Ok(Operation::Unset),
+            "$inc" => Ok(Operation::Inc(number(operator, value)?)),
+            "$mul" => Ok(Operation::Mul(number(operator, value)?)),
+            "$min" => Ok(Operation::Min(DataType::from_value(value))),
+            "$max" => Ok(Operation::Max(DataType::from_value(value))),
+            "$rename" => match value {
+                Value::String(to) if !to.is_empty() => Ok(Operation::Rename(to.clone())),
+                _ => Err("$rename expects the new name of the fields".to_string()),
+            },
+            "$push" => Ok(Operation::Push(each(value))),
+            "$addToSet" => Ok(Operation::AddToSet(each(value))),
+            "$pull" => Ok(Operation::Pull(match value {
+                Value::Object(object) if object.keys().any(|key| key.starts_with('$')) => {
+                    Pull::Condition(Filter::parse_field(ELEMENT, value)?)
+                }
+                Value::Object(_) => Pull::Document(Filter::from_value(value)?),
+                _ => Pull::Value(DataType::from_value(value)),
+            })),
+            "$currentDate" => match value {
+                Value::Bool(true) => Ok(Operation::CurrentDate { timestamp: false }),
+                Value::Object(object) => match object.get("$type").and_then(Value::as_str) {
+                    Some("date") => Ok(Operation::CurrentDate { timestamp: false }),
+                    Some("timestamp") => Ok(Operation::CurrentDate { timestamp: true }),
+                    _ => Err("$currentDate expects true, {\"$type\": \"date\"} or {\"$type\": \"timestamp\"}".to_string()),
+                },
+                _ => Err("$currentDate expects true, {\"$type\": \"date\"} or {\"$type\": \"timestamp\"}".to_string()),
+            },
+            _ => Err(format!("Unknown update operator {}", operator)),
+        }
+    }
+}
+
+// The value at the path, a missing value is created as null when `create` is true
+fn lookup<'a>(value: &'a mut DataType, segments: &[&str], create: bool) -> Result<Option<&'a mut DataType>, String> {
+    let (segment, rest) = match segments.split_first() {
+        Some(split) => split,
+        None => return Ok(Some(value)),
+    };
+    match value {
+        DataType::Document(document) => {
+            if !document.contains_key(*segment) {
+                if !create {
+                    return Ok(None);
+                }
+                let empty = if rest.is_empty() { DataType::Null } else { DataType::Document(Document::new()) };
+                document.insert(segment.to_string(), empty);
+            }
+            lookup(document.get_mut(*segment).unwrap(), rest, create)
+        }
+        DataType::Array(items) => {
+            let position: usize = match segment.parse() {
+                Ok(position) => position,
+                Err(_) if !create => return Ok(None),
+                Err(_) => return Err(format!("{} is not a position of the array", segment)),
+            };
+            if position >= items.len() {
+                if !create {
+                    return Ok(None);
+                }
+                // the array is filled with nulls up to the position
+                items.resize(position + 1, DataType::Null);
+                if !rest.is_empty() {
+                    items[position] = DataType::Document(Document::new());
+                }
+            }
+            lookup(&mut items[position], rest, create)
+        }
+        _ if create => Err(format!("Can't create the field {} in a value of type {}", segment, value.get_type())),
+        _ => Ok(None),
+    }
+}
+
+// Remove the value at the path, the elements of the arrays are set to null to keep the positions
+fn take(root: &mut DataType, segments: &[&str]) -> Result<Option<DataType>, String> {
+    let (last, parent) = segments.split_last().unwrap();
+    match lookup(root, parent, false)? {
+        Some(DataType::Document(document)) => Ok(document.remove(*last)),
+        Some(DataType::Array(items)) => Ok(last.parse::<usize>().ok()
+            .and_then(|position| items.get_mut(position))
+            .map(|item| std::mem::replace(item, DataType::Null))),
+        _ => Ok(None),
+    }
+}
+
+// Add or multiply two numbers, integers stay exact until they overflow
+// A result out of the range of the floats (infinity or NaN) can't be stored as JSON
+fn arithmetic(a: &DataType, b: &DataType, integer: fn(i64, i64) -> Option<i64>, float: fn(f64, f64) -> f64) -> Result<DataType, String> {
+    let result = match (a.as_integer(), b.as_integer()) {
+        (Some(x), Some(y)) => match integer(x, y) {
+            Some(result) => return Ok(DataType::integer(result)),
+            None => float(x as f64, y as f64),
+        },
+        _ => float(a.as_float().unwrap(), b.as_float().unwrap()),
+    };
+    if !result.is_finite() {
+        return Err(format!("The result {} is not a finite number", result));
+    }
+    Ok(DataType::Float(result))
+}
+
+fn array<'a>(value: &'a mut DataType, operator: &str, path: &str) -> Result<&'a mut Vec<DataType>, String> {
+    if *value == DataType::Null {
+        *value = DataType::Array(Vec::new());
+    }
+    match value {
+        DataType::Array(items) => Ok(items),
+        _ => Err(format!("{} expects an array at {}", operator, path)),
+    }
+}
+
+// Days since 1970-01-01 to the civil date, proleptic Gregorian calendar
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Milliseconds since the epoch as ISO 8601 text in UTC, 2024-03-01T09:30:00.000Z
+fn iso_date(millis: i64) -> String {
+    let (days, millis) = (millis.div_euclid(86_400_000), millis.rem_euclid(86_400_000));
+    let (year, month, day) = civil_from_days(days);
+    let seconds = millis / 1000;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60, millis % 1000)
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as i64)
+}
+
+impl Update {
+    // the requests come parsed, only the tests read an update from its text
+    #[cfg(test)]
+    pub fn from_json(json: &str) -> Result<Update, String> {
+        let value: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        Update::from_value(&value)
+    }
+
+    pub fn from_value(value: &Value) -> Result<Update, String> {
+        let object = match value {
+            Value::Object(object) if !object.is_empty() => object,
+            _ => return Err("An update must be a JSON object of update operators".to_string()),
+        };
+        let mut operations = Vec::new();
+        for (operator, fields) in object {
+            let fields: &Map<String, Value> = match fields {
+                Value::Object(fields) if operator.starts_with('$') => fields,
+                _ if !operator.starts_with('$') => return Err(format!("{} is not an update operator", operator)),
+                _ => return Err(format!("{} expects an object of fields", operator)),
+            };
+            for (path, value) in fields {
+                operations.push((path.clone(), Operation::parse(operator, value)?));
+            }
+        }
+        let mut paths: Vec<&str> = operations.iter().map(|(path, _)| path.as_str()).collect();
+        paths.extend(operations.iter().filter_map(|(_, operation)| match operation {
+            Operation::Rename(to) => Some(to.as_str()),
+            _ => None,
+        }));
+        for (i, path) in paths.iter().enumerate() {
+            if path.is_empty() || path.split('.').next() == Some(ID) {
+                return Err(format!("The field {:?} can't be updated", path));
+            }
+            // two operators on the same field, or on a field and a field inside it
+            let conflict = paths[i + 1..].iter().find(|other| {
+                other == &path || other.starts_with(&format!("{}.", path)) || path.starts_with(&format!("{}.", other))
+            });
+            if let Some(other) = conflict {
+                return Err(format!("The updates of {} and {} conflict", path, other));
+            }
+        }
+        Ok(Update { operations })
+    }
+
+    // Apply the operations to `document`, the document is unchanged when one of them fails
+    pub fn apply(&self, document: &mut Document) -> Result<(), String> {
+        let mut root = DataType::Document(document.clone());
+        for (path, operation) in &self.operations {
+            Update::run(&mut root, path, operation)?;
+        }
+        if let DataType::Document(updated) = root {
+            *document = updated;
+        }
+        Ok(())
+    }
+
+    fn run(root: &mut DataType, path: &str, operation: &Operation) -> Result<(), String> {
+        let segments: Vec<&str> = path.split('.').collect();
+        match operation {
+            Operation::Unset => {
+                take(root, &segments)?;
+                return Ok(());
+            }
+            Operation::Rename(to) => {
+                if let Some(value) = take(root, &segments)? {
+                    let to: Vec<&str> = to.split('.').collect();
+                    *lookup(root, &to, true)?.unwrap() = value;
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
+        let missing = lookup(root, &segments, false)?.is_none();
+        if missing && matches!(operation, Operation::Pull(_)) {
+            // nothing to remove, the path is not created
+            return Ok(());
+        }
+        let target = lookup(root, &segments, true)?.unwrap();
+        match operation {
+            Operation::Set(value) => *target = value.clone(),
+            Operation::Inc(value) | Operation::Mul(value) => {
+                let current = match &*target {
+                    _ if missing => DataType::from(0),
+                    value if value.as_float().is_some() => value.clone(),
+                    value => return Err(format!("Can't do arithmetic on {}, a value of type {}", path, value.get_type())),
+                };
+                *target = match operation {
+                    Operation::Inc(_) => arithmetic(&current, value, i64::checked_add, |a, b| a + b)?,
+                    _ => arithmetic(&current, value, i64::checked_mul, |a, b| a * b)?,
+                };
+            }
+            Operation::Min(value) => {
+                if missing || value.total_cmp(target).is_lt() {
+                    *target = value.clone();
+                }
+            }
+            Operation::Max(value) => {
+                if missing || value.total_cmp(target).is_gt() {
+                    *target = value.clone();
+                }
+            }
+            Operation::Push(values) => array(target, "$push", path)?.extend(values.iter().cloned()),
+            Operation::AddToSet(values) => {
+                let items = array(target, "$addToSet", path)?;
+                for value in values {
+                    if !items.contains(value) {
+                        items.push(value.clone());
+                    }
+                }
+            }
+            Operation::Pull(pull) => {
+                array(target, "$pull", path)?.retain(|item| !match pull {
+                    Pull::Value(value) => item == value,
+                    Pull::Condition(filter) => filter.matches(&Document::from([(ELEMENT.to_string(), item.clone())])),
+                    Pull::Document(filter) => matches!(item, DataType::Document(document) if filter.matches(document)),
+                });
+            }
+            Operation::CurrentDate { timestamp: false } => *target = DataType::Text(iso_date(now_millis())),
+            Operation::CurrentDate { timestamp: true } => *target = DataType::integer(now_millis()),
+            Operation::Unset | Operation::Rename(_) => {}
+        }
+        Ok(())
+    }
+}
+
+
+//TEST
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use crate::memodb::collection::{Document, DocumentJson};
+    use super::{iso_date, Update};
+
+    fn updated(document: &str, update: Value) -> Result<Value, String> {
+        let mut document = Document::from_json(document);
+        Update::from_value(&update)?.apply(&mut document)?;
+        Ok(serde_json::from_str(&document.to_json()).unwrap())
+    }
+
+    #[test]
+    fn update_operators() {
+        let document = r#"{
+            "name": "John", "visits": 9, "score": 2.5, "low": 3, "high": 3, "old": 1,
+            "tags": ["a", "b", "c"], "scores": [{"n": 1}, {"n": 8}], "address": {"city": "Paris"}
+        }"#;
+        assert_eq!(updated(document, json!({
+            "$set": {"address.city": "Madrid", "address.geo.lat": 40.4, "name": "Johnny"},
+            "$unset": {"scores.0": "", "missing.field": ""},
+            "$inc": {"visits": 1, "counters.likes": 2},
+            "$mul": {"score": 2},
+            "$min": {"low": 1},
+            "$max": {"high": 1},
+            "$rename": {"old": "new"},
+            "$push": {"list": {"$each": [1, 2]}},
+            "$pull": {"tags": {"$in": ["a", "c"]}},
+            "$addToSet": {"set": 1}
+        })).unwrap(), json!({
+            "name": "Johnny", "visits": 10, "score": 5.0, "low": 1, "high": 3, "new": 1,
+            "tags": ["b"], "scores": [null, {"n": 8}], "list": [1, 2], "set": [1],
+            "counters": {"likes": 2}, "address": {"city": "Madrid", "geo": {"lat": 40.4}}
+        }));
+        assert_eq!(updated(r#"{"tags": ["a"], "scores": [{"n": 1}, {"n": 8}]}"#, json!({
+            "$addToSet": {"tags": {"$each": ["a", "b"]}},
+            "$pull": {"scores": {"n": {"$gt": 5}}}
+        })).unwrap(), json!({"tags": ["a", "b"], "scores": [{"n": 1}]}));
+        // integers overflow to floats
+        assert_eq!(updated(r#"{"n": 9223372036854775807}"#, json!({"$inc": {"n": 1}})).unwrap(), json!({"n": 9223372036854775808.0}));
+    }
+
+    #[test]
+    fn missing_fields_unchanged() {
+        let document = r#"{"name": "John", "tags": ["a", "b"]}"#;
+        for update in [
+            json!({"$pull": {"a.b": 1}}),
+            json!({"$pull": {"tags.5": "x"}}),
+            json!({"$pull": {"name.first": "J"}}),
+            json!({"$unset": {"a.b": "", "tags.5": ""}}),
+        ] {
+            assert_eq!(updated(document, update).unwrap(), json!({"name": "John", "tags": ["a", "b"]}));
+        }
+    }
+
+    #[test]
+    fn invalid_updates() {
+        let document = r#"{"name": "John", "tags": "a"}"#;
+        assert!(updated(document, json!({"name": "Jane"})).is_err());
+        assert!(updated(document, json!({"$set": {"ID": 1}})).is_err());
+        assert!(updated(document, json!({"$set": {"a": 1}, "$inc": {"a.b": 1}})).is_err());
+        assert!(updated(document, json!({"$inc": {"name": 1}})).is_err());
+        assert!(updated(document, json!({"$push": {"tags": "b"}})).is_err());
+        assert!(updated(document, json!({"$set": {"name.first": "J"}})).is_err());
+        assert!(updated(document, json!({"$currentDate": {"at": 1}})).is_err());
+        // infinity and NaN can't be stored
+        assert!(updated(r#"{"n": 1e308}"#, json!({"$mul": {"n": 10}})).is_err());
+        assert!(updated(r#"{"n": 1e308}"#, json!({"$inc": {"n": 1e308}})).is_err());
+        // a failing operator doesn't change the document
+        let mut document = Document::from_json(document);
+        let update = Update::from_value(&json!({"$set": {"x": 1}, "$push": {"tags": "b"}})).unwrap();
+        assert!(update.apply(&mut document).is_err());
+        assert!(!document.contains_key("x"));
+    }
+
+    #[test]
+    fn current_date() {
+        assert_eq!(iso_date(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(iso_date(951_782_400_123), "2000-02-29T00:00:00.123Z");
+        assert_eq!(iso_date(1_709_285_400_000), "2024-03-01T09:30:00.000Z");
+        let at = updated("{}", json!({"$currentDate": {"at": true, "ms": {"$type": "timestamp"}}})).unwrap();
+        assert_eq!(at["at"].as_str().unwrap().len(), 24);
+        assert!(at["ms"].as_i64().unwrap() > 1_700_000_000_000);
+    }
+}
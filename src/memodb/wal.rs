@@ -0,0 +1,341 @@
+// The write-ahead log module keeps every mutation of the MEMOdb in an append-only file
+// Every write is appended to the log before it is applied and acknowledged,
+// at startup the log is replayed on top of the latest snapshot
+//
+// The log is a file with one JSON entry per line, each entry has a log sequence number (lsn)
+// The snapshot stores the lsn of the last write it contains, so entries already in the
+// snapshot are skipped on replay and dropped by `compact` once the snapshot is saved
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use super::collection::Document;
+use super::index::IndexSpec;
+
+// When the log is flushed to the disk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolicy {
+    // fsync before every write is acknowledged
+    Always,
+    // fsync in the background, a crash can lose the writes of the last interval
+    Interval(Duration),
+    // let the OS decide, a crash of the machine can lose any write not yet flushed
+    Never,
+}
+
+impl SyncPolicy {
+    // Parse "always", "never" or a number of milliseconds
+    pub fn parse(value: &str) -> Option<SyncPolicy> {
+        match value.trim() {
+            "always" => Some(SyncPolicy::Always),
+            "never" => Some(SyncPolicy::Never),
+            ms => ms.parse::<u64>().ok().map(|ms| SyncPolicy::Interval(Duration::from_millis(ms))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Record {
+    CreateCollection { name: String },
+    RemoveCollection { name: String },
+    Add { collection: String, document: Document },
+    Remove { collection: String, id: Uuid },
+    Update { collection: String, id: Uuid, document: Document },
+    // the whole document after an update
+    Replace { collection: String, id: Uuid, document: Document },
+    // writes applied together, a single entry of the log so they are replayed all or none
+    Batch { records: Vec<Record> },
+    CreateIndex { collection: String, index: IndexSpec },
+    DropIndex { collection: String, name: String },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    lsn: u64,
+    record: Record,
+}
+
+struct LogFile {
+    file: File,
+    // length of the file up to the end of the last entry appended
+    len: u64,
+    last_lsn: u64,
+    dirty: bool,
+    // a failed append could not be cut from the file, no more writes are accepted
+    failed: bool,
+}
+
+impl LogFile {
+    fn write_entry(&mut self, line: &[u8], policy: SyncPolicy) -> io::Result<()> {
+        self.file.write_all(line)?;
+        match policy {
+            SyncPolicy::Always => self.file.sync_data()?,
+            SyncPolicy::Interval(_) => self.dirty = true,
+            SyncPolicy::Never => {}
+        }
+        Ok(())
+    }
+}
+
+pub struct Wal {
+    path: PathBuf,
+    policy: SyncPolicy,
+    log: Mutex<LogFile>,
+}
+
+// Read every entry of the log, a torn entry at the end (crash in the middle of an append)
+// ends the log and is cut from the file
+// A complete entry that can't be read is an error, the entries after it must not be lost
+fn read_entries(path: &Path) -> io::Result<Vec<Entry>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut entries = Vec::new();
+    let mut valid_len = 0;
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line)?;
+        if read == 0 || !line.ends_with('\n') {
+            break;
+        }
+        match serde_json::from_str::<Entry>(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                let message = format!("{}: corrupt entry after lsn {}: {}", path.display(), entries.last().map_or(0, |x: &Entry| x.lsn), e);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+        }
+        valid_len += read as u64;
+    }
+    let file = reader.into_inner();
+    if file.metadata()?.len() != valid_len {
+        eprintln!("Discarding torn write-ahead log tail of {}", path.display());
+        OpenOptions::new().write(true).open(path)?.set_len(valid_len)?;
+    }
+    Ok(entries)
+}
+
+impl Wal {
+    // Open the log in `path` and return the records written after `snapshot_lsn`
+    // that must be replayed on top of the snapshot
+    pub fn open(path: &Path, policy: SyncPolicy, snapshot_lsn: u64) -> io::Result<(Arc<Wal>, Vec<Record>)> {
+        let entries = read_entries(path)?;
+        let last_lsn = entries.last().map_or(snapshot_lsn, |entry| entry.lsn.max(snapshot_lsn));
+        let records = entries
+            .into_iter()
+            .filter(|entry| entry.lsn > snapshot_lsn)
+            .map(|entry| entry.record)
+            .collect();
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let len = file.metadata()?.len();
+        let wal = Arc::new(Wal {
+            path: path.to_path_buf(),
+            policy,
+            log: Mutex::new(LogFile { file, len, last_lsn, dirty: false, failed: false }),
+        });
+        if let SyncPolicy::Interval(interval) = policy {
+            let weak = Arc::downgrade(&wal);
+            thread::spawn(move || Wal::sync_loop(weak, interval));
+        }
+        Ok((wal, records))
+    }
+
+    fn sync_loop(wal: Weak<Wal>, interval: Duration) {
+        loop {
+            thread::sleep(interval);
+            let wal = match wal.upgrade() {
+                Some(wal) => wal,
+                None => return,
+            };
+            if let Err(e) = wal.sync() {
+                eprintln!("Error syncing write-ahead log: {}", e);
+            }
+        }
+    }
+
+    // Append a record, when this returns Ok the write can be acknowledged
+    // When it fails the entry is cut from the file, so it is never replayed and the
+    // entries appended after it aren't lost behind a torn line
+    pub fn append(&self, record: &Record) -> io::Result<u64> {
+        let mut log = self.log.lock().unwrap();
+        if log.failed {
+            return Err(io::Error::other("the write-ahead log failed, restart to recover it"));
+        }
+        let entry = Entry { lsn: log.last_lsn + 1, record: record.clone() };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        if let Err(e) = log.write_entry(&line, self.policy) {
+            let len = log.len;
+            if let Err(cut) = log.file.set_len(len) {
+                eprintln!("Error cutting a failed write from the write-ahead log: {}", cut);
+                log.failed = true;
+            }
+            return Err(e);
+        }
+        log.len += line.len() as u64;
+        log.last_lsn = entry.lsn;
+        Ok(entry.lsn)
+    }
+
+    // Flush the pending writes to the disk
+    pub fn sync(&self) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        if log.dirty {
+            log.file.sync_data()?;
+            log.dirty = false;
+        }
+        Ok(())
+    }
+
+    // Sequence number of the last record appended
+    pub fn last_lsn(&self) -> u64 {
+        self.log.lock().unwrap().last_lsn
+    }
+
+    // Drop the records up to `lsn`, called once a snapshot containing them is saved
+    pub fn compact(&self, lsn: u64) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        log.file.sync_data()?;
+        log.dirty = false;
+        if log.last_lsn <= lsn {
+            log.file.set_len(0)?;
+            log.len = 0;
+            return Ok(());
+        }
+        // there are writes newer than the snapshot, keep them
+        let mut tmp_name = self.path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp = self.path.with_file_name(tmp_name);
+        let mut out = File::create(&tmp)?;
+        for entry in read_entries(&self.path)?.into_iter().filter(|entry| entry.lsn > lsn) {
+            let mut line = serde_json::to_vec(&entry)?;
+            line.push(b'\n');
+            out.write_all(&line)?;
+        }
+        out.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        log.file = OpenOptions::new().append(true).open(&self.path)?;
+        log.len = log.file.metadata()?.len();
+        Ok(())
+    }
+}
+
+
+//TEST
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use crate::doc;
+    use super::{Record, SyncPolicy, Wal};
+
+    fn temp_log() -> std::path::PathBuf {
+        env::temp_dir().join(format!("memodb-{}.wal", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn parse_sync_policy() {
+        assert_eq!(SyncPolicy::parse("always"), Some(SyncPolicy::Always));
+        assert_eq!(SyncPolicy::parse("never"), Some(SyncPolicy::Never));
+        assert_eq!(SyncPolicy::parse("250"), Some(SyncPolicy::Interval(std::time::Duration::from_millis(250))));
+        assert_eq!(SyncPolicy::parse("sometimes"), None);
+    }
+
+    #[test]
+    fn append_and_replay() {
+        let path = temp_log();
+        let (wal, records) = Wal::open(&path, SyncPolicy::Always, 0).unwrap();
+        assert!(records.is_empty());
+        wal.append(&Record::CreateCollection { name: "users".to_string() }).unwrap();
+        let add = Record::Add { collection: "users".to_string(), document: doc!{"name" => "John"} };
+        assert_eq!(wal.append(&add).unwrap(), 2);
+        drop(wal);
+
+        let (wal, records) = Wal::open(&path, SyncPolicy::Never, 1).unwrap();
+        assert_eq!(records, vec![add]);
+        assert_eq!(wal.last_lsn(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn torn_tail_is_discarded() {
+        let path = temp_log();
+        let (wal, _) = Wal::open(&path, SyncPolicy::Always, 0).unwrap();
+        wal.append(&Record::CreateCollection { name: "users".to_string() }).unwrap();
+        drop(wal);
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"lsn\":2,\"record\":{\"Create").unwrap();
+        drop(file);
+
+        let (wal, records) = Wal::open(&path, SyncPolicy::Always, 0).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(wal.append(&Record::RemoveCollection { name: "users".to_string() }).unwrap(), 2);
+        drop(wal);
+        let (_, records) = Wal::open(&path, SyncPolicy::Always, 0).unwrap();
+        assert_eq!(records.len(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_entry_is_an_error() {
+        let path = temp_log();
+        let (wal, _) = Wal::open(&path, SyncPolicy::Always, 0).unwrap();
+        wal.append(&Record::CreateCollection { name: "users".to_string() }).unwrap();
+        drop(wal);
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"lsn\":2,\"record\":{\"Float\":null}}\n").unwrap();
+        drop(file);
+        let len = fs::metadata(&path).unwrap().len();
+
+        let error = Wal::open(&path, SyncPolicy::Always, 0).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn failed_append_is_not_replayed() {
+        let path = temp_log();
+        let (wal, _) = Wal::open(&path, SyncPolicy::Always, 0).unwrap();
+        wal.append(&Record::CreateCollection { name: "users".to_string() }).unwrap();
+        // a full disk that can't be cut either, the log refuses the writes after it
+        let file = std::mem::replace(&mut wal.log.lock().unwrap().file, fs::OpenOptions::new().append(true).open("/dev/full").unwrap());
+        assert!(wal.append(&Record::RemoveCollection { name: "users".to_string() }).is_err());
+        wal.log.lock().unwrap().file = file;
+        assert!(wal.append(&Record::RemoveCollection { name: "users".to_string() }).is_err());
+        assert_eq!(wal.last_lsn(), 1);
+        drop(wal);
+        let (_, records) = Wal::open(&path, SyncPolicy::Always, 0).unwrap();
+        assert_eq!(records, vec![Record::CreateCollection { name: "users".to_string() }]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compact_keeps_newer_records() {
+        let path = temp_log();
+        let (wal, _) = Wal::open(&path, SyncPolicy::Always, 0).unwrap();
+        for name in ["a", "b", "c"] {
+            wal.append(&Record::CreateCollection { name: name.to_string() }).unwrap();
+        }
+        wal.compact(2).unwrap();
+        wal.append(&Record::CreateCollection { name: "d".to_string() }).unwrap();
+        drop(wal);
+        let (_, records) = Wal::open(&path, SyncPolicy::Always, 2).unwrap();
+        assert_eq!(records, vec![
+            Record::CreateCollection { name: "c".to_string() },
+            Record::CreateCollection { name: "d".to_string() },
+        ]);
+        fs::remove_file(&path).unwrap();
+    }
+}